@@ -0,0 +1,274 @@
+use crate::machine::{ErrorKind, Instruction, Register, State};
+use crate::program::ResolvedProgram;
+
+/// A lowered instruction. Registers are plain indices and jumps carry the
+/// address they go to, so an `Op` is 8 bytes and needs no lookups to run.
+///
+/// Only the instructions that show up in hot loops get their own op. The rest
+/// are `Other` and run through the normal interpreter, which keeps the two
+/// engines from drifting apart on the rarely used instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Set(u8, u32),
+    Mov(u8, u8),
+    Add(u8, u8, u8),
+    Adc(u8, u8, u8),
+    Sub(u8, u8, u8),
+    Sbc(u8, u8, u8),
+    And(u8, u8, u8),
+    Or(u8, u8, u8),
+    Xor(u8, u8, u8),
+    Inc(u8),
+    Dec(u8),
+    Not(u8),
+    AddI(u8, u8, u32),
+    SubI(u8, u8, u32),
+    AndI(u8, u8, u32),
+    OrI(u8, u8, u32),
+    XorI(u8, u8, u32),
+    Cmp(u8, u8),
+    CmpI(u8, u32),
+    Load(u8, u8),
+    Store(u8, u8),
+    LoadB(u8, u8),
+    StoreB(u8, u8),
+    J(u32),
+    Jz(u32),
+    Jnz(u32),
+    Jc(u32),
+    Jnc(u32),
+    Jn(u32),
+    Jv(u32),
+    Jlt(u32),
+    Jge(u32),
+    /// Anything else, run by `Machine::step`'s interpreter.
+    Other,
+}
+
+/// A `ResolvedProgram` lowered to `Op`s, one per instruction address.
+#[derive(Debug, Clone, Default)]
+pub struct Bytecode {
+    ops: Vec<Op>,
+}
+
+impl Bytecode {
+    pub fn lower(program: &ResolvedProgram) -> Bytecode {
+        let ops = program
+            .instructions()
+            .iter()
+            .enumerate()
+            .map(|(address, instruction)| {
+                lower_instruction(instruction, program.target(address) as u32)
+            })
+            .collect();
+        Bytecode { ops }
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+}
+
+fn lower_instruction(instruction: &Instruction, target: u32) -> Op {
+    match *instruction {
+        Instruction::Zero(Register(d)) => Op::Set(d, 0),
+        Instruction::Set(Register(d), k) => Op::Set(d, k),
+        Instruction::Mov(Register(d), Register(a)) => Op::Mov(d, a),
+        Instruction::Add(Register(d), Register(a), Register(b)) => Op::Add(d, a, b),
+        Instruction::Adc(Register(d), Register(a), Register(b)) => Op::Adc(d, a, b),
+        Instruction::Sub(Register(d), Register(a), Register(b)) => Op::Sub(d, a, b),
+        Instruction::Sbc(Register(d), Register(a), Register(b)) => Op::Sbc(d, a, b),
+        Instruction::And(Register(d), Register(a), Register(b)) => Op::And(d, a, b),
+        Instruction::Or(Register(d), Register(a), Register(b)) => Op::Or(d, a, b),
+        Instruction::Xor(Register(d), Register(a), Register(b)) => Op::Xor(d, a, b),
+        Instruction::Inc(Register(d)) => Op::Inc(d),
+        Instruction::Dec(Register(d)) => Op::Dec(d),
+        Instruction::Not(Register(d)) => Op::Not(d),
+        Instruction::AddI(Register(d), Register(a), k) => Op::AddI(d, a, k),
+        Instruction::SubI(Register(d), Register(a), k) => Op::SubI(d, a, k),
+        Instruction::AndI(Register(d), Register(a), k) => Op::AndI(d, a, k),
+        Instruction::OrI(Register(d), Register(a), k) => Op::OrI(d, a, k),
+        Instruction::XorI(Register(d), Register(a), k) => Op::XorI(d, a, k),
+        Instruction::Cmp(Register(a), Register(b)) => Op::Cmp(a, b),
+        Instruction::CmpI(Register(a), k) => Op::CmpI(a, k),
+        Instruction::Load(Register(d), Register(a)) => Op::Load(d, a),
+        Instruction::Store(Register(s), Register(a)) => Op::Store(s, a),
+        Instruction::LoadB(Register(d), Register(a)) => Op::LoadB(d, a),
+        Instruction::StoreB(Register(s), Register(a)) => Op::StoreB(s, a),
+        Instruction::J(_) => Op::J(target),
+        Instruction::Jz(_) => Op::Jz(target),
+        Instruction::Jnz(_) => Op::Jnz(target),
+        Instruction::Jc(_) | Instruction::Jb(_) => Op::Jc(target),
+        Instruction::Jnc(_) | Instruction::Jae(_) => Op::Jnc(target),
+        Instruction::Jn(_) => Op::Jn(target),
+        Instruction::Jv(_) => Op::Jv(target),
+        Instruction::Jlt(_) => Op::Jlt(target),
+        Instruction::Jge(_) => Op::Jge(target),
+        _ => Op::Other,
+    }
+}
+
+impl State {
+    /// Runs `code` until the program stops and returns why it stopped, or
+    /// `None` once `fuel` steps have been used up. Every op has the same effect
+    /// as the instruction it was lowered from.
+    pub(crate) fn run_bytecode(
+        &mut self,
+        code: &Bytecode,
+        program: &ResolvedProgram,
+        fuel: &mut u64,
+    ) -> Option<ErrorKind> {
+        loop {
+            if *fuel == 0 {
+                return None;
+            }
+            let Some(op) = code.ops.get(self.index) else {
+                return Some(ErrorKind::EndOfProgram);
+            };
+            *fuel -= 1;
+            let moved = match *op {
+                Op::Set(d, k) => self.write(d, k),
+                Op::Mov(d, a) => self.write(d, self.registers[a as usize]),
+                Op::Add(d, a, b) => {
+                    let res = self.add_with_carry(
+                        self.registers[a as usize],
+                        self.registers[b as usize],
+                        false,
+                    );
+                    self.write(d, res)
+                }
+                Op::Adc(d, a, b) => {
+                    let res = self.add_with_carry(
+                        self.registers[a as usize],
+                        self.registers[b as usize],
+                        self.flags.carry,
+                    );
+                    self.write(d, res)
+                }
+                Op::Sub(d, a, b) => {
+                    let res = self.sub_with_borrow(
+                        self.registers[a as usize],
+                        self.registers[b as usize],
+                        false,
+                    );
+                    self.write(d, res)
+                }
+                Op::Sbc(d, a, b) => {
+                    let res = self.sub_with_borrow(
+                        self.registers[a as usize],
+                        self.registers[b as usize],
+                        self.flags.carry,
+                    );
+                    self.write(d, res)
+                }
+                Op::And(d, a, b) => {
+                    self.write_logic(d, self.registers[a as usize] & self.registers[b as usize])
+                }
+                Op::Or(d, a, b) => {
+                    self.write_logic(d, self.registers[a as usize] | self.registers[b as usize])
+                }
+                Op::Xor(d, a, b) => {
+                    self.write_logic(d, self.registers[a as usize] ^ self.registers[b as usize])
+                }
+                Op::Inc(d) => {
+                    let carry = self.flags.carry;
+                    let res = self.add_with_carry(self.registers[d as usize], 1, false);
+                    self.flags.carry = carry;
+                    self.write(d, res)
+                }
+                Op::Dec(d) => {
+                    let carry = self.flags.carry;
+                    let res = self.sub_with_borrow(self.registers[d as usize], 1, false);
+                    self.flags.carry = carry;
+                    self.write(d, res)
+                }
+                Op::Not(d) => self.write_logic(d, !self.registers[d as usize] & self.width.mask()),
+                Op::AddI(d, a, k) => {
+                    let res = self.add_with_carry(self.registers[a as usize], k, false);
+                    self.write(d, res)
+                }
+                Op::SubI(d, a, k) => {
+                    let res = self.sub_with_borrow(self.registers[a as usize], k, false);
+                    self.write(d, res)
+                }
+                Op::AndI(d, a, k) => self.write_logic(d, self.registers[a as usize] & k),
+                Op::OrI(d, a, k) => self.write_logic(d, self.registers[a as usize] | k),
+                Op::XorI(d, a, k) => self.write_logic(d, self.registers[a as usize] ^ k),
+                Op::Cmp(a, b) => {
+                    let b = self.registers[b as usize];
+                    self.sub_with_borrow(self.registers[a as usize], b, false);
+                    false
+                }
+                Op::CmpI(a, k) => {
+                    self.sub_with_borrow(self.registers[a as usize], k, false);
+                    false
+                }
+                Op::Load(d, a) => match self
+                    .read_memory(self.registers[a as usize] as usize, self.width.bytes())
+                {
+                    Ok(val) => self.write(d, val),
+                    Err(e) => return Some(e),
+                },
+                Op::LoadB(d, a) => match self.read_memory(self.registers[a as usize] as usize, 1) {
+                    Ok(val) => self.write(d, val),
+                    Err(e) => return Some(e),
+                },
+                Op::Store(s, a) => {
+                    let address = self.registers[a as usize] as usize;
+                    if let Err(e) =
+                        self.write_memory(address, self.width.bytes(), self.registers[s as usize])
+                    {
+                        return Some(e);
+                    }
+                    false
+                }
+                Op::StoreB(s, a) => {
+                    let address = self.registers[a as usize] as usize;
+                    if let Err(e) = self.write_memory(address, 1, self.registers[s as usize]) {
+                        return Some(e);
+                    }
+                    false
+                }
+                Op::J(t) => self.branch(true, t),
+                Op::Jz(t) => self.branch(self.flags.zero, t),
+                Op::Jnz(t) => self.branch(!self.flags.zero, t),
+                Op::Jc(t) => self.branch(self.flags.carry, t),
+                Op::Jnc(t) => self.branch(!self.flags.carry, t),
+                Op::Jn(t) => self.branch(self.flags.negative, t),
+                Op::Jv(t) => self.branch(self.flags.overflow, t),
+                Op::Jlt(t) => self.branch(self.flags.negative != self.flags.overflow, t),
+                Op::Jge(t) => self.branch(self.flags.negative == self.flags.overflow, t),
+                Op::Other => {
+                    let instruction = &program.instructions()[self.index];
+                    if let Err(e) = self.intepret_instruction(instruction, program) {
+                        return Some(e);
+                    }
+                    true
+                }
+            };
+            if !moved {
+                self.index += 1;
+            }
+        }
+    }
+
+    /// Writes a register. Returns false, meaning the index has not moved yet,
+    /// so that ops can end with it.
+    fn write(&mut self, register: u8, value: u32) -> bool {
+        self.modify_register(&Register(register), value);
+        false
+    }
+
+    fn write_logic(&mut self, register: u8, value: u32) -> bool {
+        self.set_logic_flags(value);
+        self.write(register, value)
+    }
+
+    /// Moves to `target` if `condition` holds, and tells whether it did.
+    fn branch(&mut self, condition: bool, target: u32) -> bool {
+        if condition {
+            self.index = target as usize;
+        }
+        condition
+    }
+}
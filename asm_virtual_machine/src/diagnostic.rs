@@ -0,0 +1,142 @@
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use crate::machine::Span;
+
+/// A problem with the source of a program, pointing at the text that caused
+/// it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Shows the message above the offending source line, with the problem
+    /// underlined:
+    ///
+    /// ```text
+    /// error: unknown mnemonic 'ADDD', did you mean 'ADD'?
+    ///  --> main.asm:3:5
+    ///   |
+    /// 3 |     ADDD R1, R2, R3
+    ///   |     ^^^^
+    /// ```
+    ///
+    /// A problem inside a macro also shows every call that led to it.
+    pub fn render(&self, source: &str) -> String {
+        self.render_with(&|_| source)
+    }
+
+    /// Like `render`, with the source of each span looked up by `source`.
+    fn render_with<'s>(&self, source: &dyn Fn(&Span) -> &'s str) -> String {
+        let mut text = format!(
+            "error: {}\n{}",
+            self.message,
+            snippet(&self.span, source(&self.span))
+        );
+        let mut expansion = &self.span.expansion;
+        while let Some(call) = expansion {
+            text += &format!(
+                "note: in this call of macro {}\n{}",
+                call.name,
+                snippet(&call.call, source(&call.call))
+            );
+            expansion = &call.call.expansion;
+        }
+        text
+    }
+}
+
+/// The source line of `span` with the spanned text underlined.
+fn snippet(span: &Span, source: &str) -> String {
+    let line = source
+        .lines()
+        .nth(span.line.saturating_sub(1))
+        .unwrap_or("");
+    let gutter = span.line.to_string().len();
+    // Tabs are kept so that the carets line up with the source.
+    let indent: String = line
+        .chars()
+        .take(span.column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let file = match &span.file {
+        Some(file) => format!("{}:", file),
+        None => String::new(),
+    };
+    let length = span.end_column.saturating_sub(span.column);
+    format!(
+        "{:gutter$}--> {}{}:{}\n{:gutter$} |\n{} | {}\n{:gutter$} | {}{}\n",
+        "",
+        file,
+        span.line,
+        span.column,
+        "",
+        span.line,
+        line,
+        "",
+        indent,
+        "^".repeat(length.max(1)),
+    )
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.span.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.span.line, self.span.column)?,
+            None => write!(f, "line {}, column {}", self.span.line, self.span.column)?,
+        }
+        if let Some(expansion) = &self.span.expansion {
+            write!(
+                f,
+                " in macro {} called at {}",
+                expansion.name, expansion.call
+            )?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// The text of source files, by file name.
+pub(crate) type Sources = HashMap<Arc<str>, Arc<str>>;
+
+/// Everything that is wrong with a program, in source order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub diagnostics: Vec<Diagnostic>,
+    /// The text of every file the diagnostics can point into, by name.
+    pub(crate) sources: Sources,
+}
+
+impl ParseError {
+    /// Renders every diagnostic with `Diagnostic::render`. Lines of included
+    /// files and of other modules are taken from the copy kept in the error,
+    /// and `source` is only needed for a program without a file name.
+    pub fn render(&self, source: &str) -> String {
+        let source = |span: &Span| {
+            span.file
+                .as_ref()
+                .and_then(|file| self.sources.get(file))
+                .map_or(source, |text| &**text)
+        };
+        self.diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.render_with(&source))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (n, diagnostic) in self.diagnostics.iter().enumerate() {
+            if n > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
@@ -0,0 +1,404 @@
+//! Formats assembly source the canonical way.
+//!
+//! The source is read with the assembler's own grammar and the tree is
+//! printed again, rather than printing the parsed `ProgramLine`s with
+//! `Display`: those have their constants evaluated, their macros expanded
+//! and their comments dropped, and the formatter must keep all of that.
+
+use std::collections::HashSet;
+
+use pest::{iterators::Pair, Parser};
+
+use crate::diagnostic::{Diagnostic, ParseError, Sources};
+use crate::machine::{MachineConfig, ProgramLine, Register, Span};
+use crate::parser::{parse_file, parse_program_with_config, ASMProgramParser, Rule};
+
+/// How far instructions are indented below their labels, and everything in
+/// a macro below the `.macro` line.
+const INDENT: usize = 4;
+
+/// Mnemonics are padded to this width so that operands line up.
+const MNEMONIC_WIDTH: usize = 7;
+
+/// Statements that stay at the margin like labels, as they do not put
+/// anything in the program where they are.
+const MARGIN_STATEMENTS: &[Rule] = &[
+    Rule::MacroDirective,
+    Rule::EndmDirective,
+    Rule::IncludeDirective,
+    Rule::EquDirective,
+    Rule::AliasDirective,
+    Rule::GlobalDirective,
+    Rule::DataDirective,
+    Rule::TextDirective,
+];
+
+/// Lays out a program the canonical way:
+///
+/// ```text
+/// .equ COUNT, 3              ; directives at the margin
+/// loop:
+///     SUBI    R1, R1, COUNT  ; instructions below their labels
+///     JNZ     loop
+/// ```
+///
+/// Mnemonics are written in upper case and directives in lower case, one
+/// label goes on each line, operands are separated by ", " and the comments
+/// of a paragraph are lined up. Expressions get one space around each
+/// operator, but constants, expressions and macro calls stay in the source.
+///
+/// The program is parsed before and after, and if the two do not assemble
+/// to the same program the source is not changed and an error says where
+/// they differ. A program with errors is not formatted either.
+pub fn format_program(source: &str, config: &MachineConfig) -> Result<String, ParseError> {
+    format(source, |source| parse_program_with_config(source, config))
+}
+
+/// Like `format_program`, for a program read from the file `name`, so that
+/// its `.include`s are found next to it.
+pub fn format_file(name: &str, source: &str, config: &MachineConfig) -> Result<String, ParseError> {
+    format(source, |source| parse_file(name, source, config))
+}
+
+fn format(
+    source: &str,
+    parse: impl Fn(&str) -> Result<Vec<ProgramLine>, ParseError>,
+) -> Result<String, ParseError> {
+    let before = parse(source)?;
+    let formatted = layout(source);
+    let meaning_before = meaning(&before);
+    let changed = match parse(&formatted) {
+        Ok(after) => {
+            let after = meaning(&after);
+            if after == meaning_before {
+                return Ok(formatted);
+            }
+            meaning_before
+                .iter()
+                .zip(&after)
+                .position(|(before, after)| before != after)
+                .unwrap_or(after.len())
+        }
+        Err(_) => 0,
+    };
+    let span = before
+        .get(changed)
+        .or(before.last())
+        .map_or_else(Span::default, |line| line.span().clone());
+    Err(ParseError {
+        diagnostics: vec![Diagnostic {
+            span,
+            message:
+                "formatting would change what the program does from here, so it was left as it is"
+                    .to_string(),
+        }],
+        sources: Sources::new(),
+    })
+}
+
+/// What a program does, line by line, without where the lines came from.
+fn meaning(lines: &[ProgramLine]) -> Vec<String> {
+    lines
+        .iter()
+        .map(|line| match line {
+            ProgramLine::Ins(instruction, _) => instruction.to_string(),
+            ProgramLine::Lbl(label, _) => format!("{}:", label.0),
+            ProgramLine::Alias(name, register, _) => format!(".alias {} {}", name, register),
+            ProgramLine::Global(label, _) => format!(".global {}", label.0),
+            ProgramLine::Data(address, bytes, _) => format!("{}: {:?}", address, bytes),
+            ProgramLine::DataLabel(label, address, _) => format!("{} = {}", label.0, address),
+        })
+        .collect()
+}
+
+/// A line of formatted code, before the comments are lined up. A line
+/// without code or comment is a blank line.
+struct Line {
+    indent: usize,
+    code: String,
+    comment: Option<String>,
+}
+
+impl Line {
+    fn width(&self) -> usize {
+        self.indent + self.code.chars().count()
+    }
+}
+
+/// The names that look like registers but are not, as they are aliases,
+/// labels or constants.
+struct Names {
+    defined: HashSet<String>,
+    /// The parameters of the macro being read.
+    params: Vec<String>,
+}
+
+impl Names {
+    /// Writes `operand` as `R1` if it is a register written another way,
+    /// like `r1`.
+    fn register(&self, operand: &str) -> String {
+        match Register::try_from(operand) {
+            Ok(register)
+                if !self.defined.contains(operand)
+                    && !self.params.iter().any(|param| param == operand) =>
+            {
+                register.to_string()
+            }
+            _ => operand.to_string(),
+        }
+    }
+
+    /// Writes an operand of an instruction, with registers in upper case
+    /// also where a constant could go, like inside `[...]`.
+    fn operand(&self, operand: Pair<Rule>) -> String {
+        match operand.as_rule() {
+            Rule::register => self.register(operand.as_str()),
+            Rule::Address => match operand.into_inner().next() {
+                Some(inner) => format!("[{}]", self.operand(inner)),
+                None => "[]".to_string(),
+            },
+            Rule::Expression => {
+                let mut parts = operand.clone().into_inner();
+                match (parts.next(), parts.next()) {
+                    (Some(symbol), None) if symbol.as_rule() == Rule::Symbol => {
+                        self.register(symbol.as_str())
+                    }
+                    _ => expression(operand),
+                }
+            }
+            _ => plain(operand),
+        }
+    }
+
+    /// Writes an argument of a macro call, which becomes an operand when
+    /// the macro is expanded, so its registers are written the same way.
+    fn argument(&self, argument: Pair<Rule>) -> String {
+        match argument.into_inner().next() {
+            Some(inner) if inner.as_rule() == Rule::identifier => self.register(inner.as_str()),
+            Some(inner) => self.operand(inner),
+            None => String::new(),
+        }
+    }
+
+    /// The code of a statement: the mnemonic, directive or macro call with
+    /// its operands.
+    fn statement(&self, statement: Pair<Rule>) -> String {
+        let rule = statement.as_rule();
+        let mut parts = statement.into_inner();
+        let Some(name) = parts.next() else {
+            return String::new();
+        };
+        let (word, operands) = match rule {
+            // A macro call, whose name is kept as it is written.
+            Rule::MacroCall => {
+                let operands: Vec<String> = parts.map(|argument| self.argument(argument)).collect();
+                (name.as_str().to_string(), operands.join(", "))
+            }
+            Rule::MacroDirective => {
+                let operands: Vec<String> = parts.map(|param| param.as_str().to_string()).collect();
+                let operands = match operands.split_first() {
+                    Some((name, params)) => format!("{} {}", name, params.join(", ")),
+                    None => String::new(),
+                };
+                (".macro".to_string(), operands.trim_end().to_string())
+            }
+            Rule::EquDirective => {
+                let directive = name.as_str().to_lowercase();
+                let separator = if directive == ".equ" { ", " } else { " " };
+                let operands: Vec<String> = parts.map(plain).collect();
+                (directive, operands.join(separator))
+            }
+            Rule::AliasDirective => {
+                let alias = parts.next().map_or("", |alias| alias.as_str());
+                let register = parts
+                    .next()
+                    .map(|register| self.register(register.as_str()));
+                let operands = format!("{} {}", alias, register.unwrap_or_default());
+                (".alias".to_string(), operands.trim_end().to_string())
+            }
+            _ if name.as_str().starts_with('.') => {
+                let operands: Vec<String> = parts.map(plain).collect();
+                (name.as_str().to_lowercase(), operands.join(", "))
+            }
+            _ => {
+                let operands: Vec<String> = parts.map(|operand| self.operand(operand)).collect();
+                (name.as_str().to_uppercase(), operands.join(", "))
+            }
+        };
+        if MARGIN_STATEMENTS.contains(&rule) || operands.is_empty() {
+            format!("{} {}", word, operands).trim_end().to_string()
+        } else {
+            format!("{:width$} {}", word, operands, width = MNEMONIC_WIDTH)
+        }
+    }
+}
+
+/// Writes an expression with one space around each operator and none inside
+/// parentheses. Names and literals are kept as they are written.
+fn expression(expression: Pair<Rule>) -> String {
+    let mut text = String::new();
+    for part in expression.into_inner() {
+        match part.as_rule() {
+            Rule::Expression => text += &format!("({})", self::expression(part)),
+            Rule::Neg | Rule::BitNot | Rule::Literal | Rule::Symbol => text += part.as_str(),
+            _ => text += &format!(" {} ", part.as_str()),
+        }
+    }
+    text
+}
+
+/// Writes an operand that is never a register, like a value of `.byte` or
+/// `.equ`.
+fn plain(operand: Pair<Rule>) -> String {
+    match operand.as_rule() {
+        Rule::Expression => expression(operand),
+        Rule::Address => match operand.into_inner().next() {
+            Some(inner) => format!("[{}]", plain(inner)),
+            None => "[]".to_string(),
+        },
+        _ => operand.as_str().to_string(),
+    }
+}
+
+/// A comment with its `#` or `//` written as `;`.
+fn comment(remark: Pair<Rule>) -> String {
+    let text = remark.as_str().trim_end();
+    let rest = text
+        .strip_prefix("//")
+        .or_else(|| text.strip_prefix([';', '#']))
+        .unwrap_or(text);
+    format!(";{}", rest)
+}
+
+/// The names a line defines that are not a register even if it looks like one:
+/// its labels, or the name of an alias or constant.
+fn defined_names(line: Pair<Rule>) -> Vec<String> {
+    line.into_inner()
+        .filter_map(|part| match part.as_rule() {
+            Rule::label => part.into_inner().next(),
+            Rule::AliasDirective | Rule::EquDirective => part.into_inner().nth(1),
+            _ => None,
+        })
+        .map(|name| name.as_str().to_string())
+        .collect()
+}
+
+/// Formats every line of `source`, without checking what it means. A line
+/// the formatter cannot read is kept as it is.
+fn layout(source: &str) -> String {
+    let Ok(program) = ASMProgramParser::parse(Rule::SourceProgram, source) else {
+        return source.to_string();
+    };
+    let program: Vec<Pair<Rule>> = program.collect();
+    let mut names = Names {
+        defined: program.iter().cloned().flat_map(defined_names).collect(),
+        params: Vec::new(),
+    };
+
+    let mut lines: Vec<Line> = Vec::new();
+    let mut in_macro = false;
+    for line in program {
+        let mut labels = Vec::new();
+        let mut statement = None;
+        let mut remark = None;
+        for part in line.clone().into_inner() {
+            match part.as_rule() {
+                Rule::label => labels.extend(part.into_inner().next()),
+                Rule::Remark => remark = Some(part),
+                _ => statement = Some(part),
+            }
+        }
+        let statement_rule = statement.as_ref().map(Pair::as_rule);
+        if statement_rule == Some(Rule::EndmDirective) {
+            in_macro = false;
+            names.params.clear();
+        }
+        let margin = if in_macro { INDENT } else { 0 };
+        let comment = remark.map(comment);
+
+        match line.as_rule() {
+            Rule::SourceLine => {}
+            Rule::SourceBlank => {
+                let indented = line.as_span().start_pos().line_col().1 > 1;
+                lines.push(Line {
+                    indent: if comment.is_some() && indented {
+                        margin + INDENT
+                    } else {
+                        0
+                    },
+                    code: String::new(),
+                    comment,
+                });
+                continue;
+            }
+            Rule::BadLine => {
+                lines.push(Line {
+                    indent: 0,
+                    code: line.as_str().trim_end().to_string(),
+                    comment: None,
+                });
+                continue;
+            }
+            _ => continue,
+        }
+        for label in &labels {
+            lines.push(Line {
+                indent: margin,
+                code: format!("{}:", label.as_str()),
+                comment: None,
+            });
+        }
+        let Some(statement) = statement else {
+            if let Some(label) = lines.last_mut() {
+                label.comment = comment;
+            }
+            continue;
+        };
+        if statement_rule == Some(Rule::MacroDirective) {
+            in_macro = true;
+            let params = statement.clone().into_inner().skip(2);
+            names.params = params.map(|param| param.as_str().to_string()).collect();
+        }
+        let indent = match statement_rule {
+            Some(rule) if MARGIN_STATEMENTS.contains(&rule) => margin,
+            _ => margin + INDENT,
+        };
+        lines.push(Line {
+            indent,
+            code: names.statement(statement),
+            comment,
+        });
+    }
+    render(lines)
+}
+
+/// Writes out `lines` with at most one blank line in a row, and the
+/// comments after code lined up within each paragraph.
+fn render(lines: Vec<Line>) -> String {
+    let is_blank = |line: &Line| line.code.is_empty() && line.comment.is_none();
+    let mut text = String::new();
+    for paragraph in lines.split(is_blank).filter(|lines| !lines.is_empty()) {
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        let column = paragraph
+            .iter()
+            .filter(|line| !line.code.is_empty() && line.comment.is_some())
+            .map(Line::width)
+            .max()
+            .unwrap_or(0)
+            + 2;
+        for line in paragraph {
+            let mut out = format!("{:indent$}{}", "", line.code, indent = line.indent);
+            if let Some(comment) = &line.comment {
+                if !line.code.is_empty() {
+                    out += &" ".repeat(column - line.width());
+                }
+                out += comment;
+            }
+            text += &out;
+            text.push('\n');
+        }
+    }
+    text
+}
@@ -0,0 +1,140 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{BufRead, Read, Write},
+    rc::Rc,
+};
+
+/// Something a program can talk to with `IN` and `OUT`.
+///
+/// A device is attached to one or more ports with `Machine::attach_device`
+/// and is told which port the program used.
+pub trait IoDevice {
+    /// Produces the next value for `IN`, or `None` if there is nothing to read.
+    fn read(&mut self, port: u8) -> Option<u32>;
+
+    /// Receives the value written by `OUT`.
+    fn write(&mut self, port: u8, value: u32);
+}
+
+/// How a `Console` turns values into text and back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleMode {
+    /// One byte per value, so `OUT` prints characters.
+    Characters,
+    /// One decimal number per line.
+    Numbers,
+}
+
+/// Reads from stdin and writes to stdout.
+pub struct Console {
+    mode: ConsoleMode,
+}
+
+impl Console {
+    pub fn new(mode: ConsoleMode) -> Console {
+        Console { mode }
+    }
+}
+
+impl IoDevice for Console {
+    fn read(&mut self, _port: u8) -> Option<u32> {
+        match self.mode {
+            ConsoleMode::Characters => {
+                let mut byte = [0];
+                match std::io::stdin().read(&mut byte) {
+                    Ok(1) => Some(byte[0] as u32),
+                    _ => None,
+                }
+            }
+            ConsoleMode::Numbers => {
+                let mut line = String::new();
+                std::io::stdin().lock().read_line(&mut line).ok()?;
+                line.trim().parse::<i64>().ok().map(|n| n as u32)
+            }
+        }
+    }
+
+    fn write(&mut self, _port: u8, value: u32) {
+        let mut stdout = std::io::stdout();
+        let _ = match self.mode {
+            ConsoleMode::Characters => stdout.write_all(&[value as u8]),
+            ConsoleMode::Numbers => writeln!(stdout, "{}", value),
+        };
+        let _ = stdout.flush();
+    }
+}
+
+/// Hands out a fixed list of values, for tests and scripted runs. Writes are
+/// ignored.
+#[derive(Debug, Clone, Default)]
+pub struct InputQueue {
+    values: VecDeque<u32>,
+}
+
+impl InputQueue {
+    pub fn new(values: impl IntoIterator<Item = u32>) -> InputQueue {
+        InputQueue {
+            values: values.into_iter().collect(),
+        }
+    }
+
+    /// Queues the bytes of `text`, one value per byte.
+    pub fn from_text(text: &str) -> InputQueue {
+        InputQueue::new(text.bytes().map(|b| b as u32))
+    }
+}
+
+impl IoDevice for InputQueue {
+    fn read(&mut self, _port: u8) -> Option<u32> {
+        self.values.pop_front()
+    }
+
+    fn write(&mut self, _port: u8, _value: u32) {}
+}
+
+/// Records everything written to it. Clones share the same buffer, so keep
+/// one clone to inspect the output after attaching another to the machine.
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer {
+    values: Rc<RefCell<Vec<(u8, u32)>>>,
+}
+
+impl OutputBuffer {
+    pub fn new() -> OutputBuffer {
+        OutputBuffer::default()
+    }
+
+    /// All values written so far, in order.
+    pub fn values(&self) -> Vec<u32> {
+        self.values.borrow().iter().map(|(_, v)| *v).collect()
+    }
+
+    /// All values written so far together with the port they were written to.
+    pub fn writes(&self) -> Vec<(u8, u32)> {
+        self.values.borrow().clone()
+    }
+
+    /// The output read as one byte per value.
+    pub fn text(&self) -> String {
+        self.values
+            .borrow()
+            .iter()
+            .map(|(_, v)| *v as u8 as char)
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.values.borrow_mut().clear();
+    }
+}
+
+impl IoDevice for OutputBuffer {
+    fn read(&mut self, _port: u8) -> Option<u32> {
+        None
+    }
+
+    fn write(&mut self, port: u8, value: u32) {
+        self.values.borrow_mut().push((port, value));
+    }
+}
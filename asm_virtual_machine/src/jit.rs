@@ -0,0 +1,608 @@
+//! Compiles bytecode to native code with Cranelift.
+//!
+//! Every op becomes a block of machine code that keeps the registers and
+//! flags in host registers. Ops the compiler does not handle (`Op::Other`)
+//! leave the compiled code, are run by the interpreter, and the compiled code
+//! is entered again at the next address.
+
+use std::mem::offset_of;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, BlockCall, Endianness, InstBuilder, JumpTableData, MemFlags, Type,
+    Value,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+use crate::bytecode::{Bytecode, Op};
+use crate::machine::{ErrorKind, Flags, State};
+use crate::program::ResolvedProgram;
+use crate::word::WordWidth;
+
+/// Everything the compiled code reads and writes, shared with it by pointer.
+#[repr(C)]
+struct Context {
+    registers: *mut u32,
+    memory: *mut u8,
+    memory_len: u64,
+    index: u64,
+    fuel: u64,
+    /// Address of the failed access after `EXIT_MEMORY`.
+    address: u64,
+    zero: u8,
+    carry: u8,
+    negative: u8,
+    overflow: u8,
+}
+
+/// Why the compiled code returned.
+const EXIT_END: i64 = 0;
+const EXIT_INTERPRET: i64 = 1;
+const EXIT_MEMORY: i64 = 2;
+const EXIT_OUT_OF_FUEL: i64 = 3;
+
+type Entry = unsafe extern "C" fn(*mut Context) -> u32;
+
+/// A program compiled to native code for one word width and register count.
+pub struct JitProgram {
+    /// Owns the memory `entry` points into.
+    module: Option<JITModule>,
+    entry: Entry,
+}
+
+impl JitProgram {
+    /// Compiles `code`. Returns `None` if Cranelift does not support the host.
+    pub fn compile(code: &Bytecode, width: WordWidth, register_count: usize) -> Option<JitProgram> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").ok()?;
+        let isa = cranelift_native::builder()
+            .ok()?
+            .finish(settings::Flags::new(flags))
+            .ok()?;
+        let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        let pointer = module.target_config().pointer_type();
+        let mut context = module.make_context();
+        context.func.signature.params.push(AbiParam::new(pointer));
+        context
+            .func
+            .signature
+            .returns
+            .push(AbiParam::new(types::I32));
+        let mut builder_context = FunctionBuilderContext::new();
+        let builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        Compiler::new(builder, pointer, width, register_count).compile(code.ops());
+
+        let id = module
+            .declare_function("run", Linkage::Local, &context.func.signature)
+            .ok()?;
+        module.define_function(id, &mut context).ok()?;
+        module.clear_context(&mut context);
+        module.finalize_definitions().ok()?;
+        let code = module.get_finalized_function(id);
+        // SAFETY: the function was declared with exactly this signature.
+        let entry = unsafe { std::mem::transmute::<*const u8, Entry>(code) };
+        Some(JitProgram {
+            module: Some(module),
+            entry,
+        })
+    }
+}
+
+impl Drop for JitProgram {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: `entry` goes away together with the module.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+struct Compiler<'a> {
+    builder: FunctionBuilder<'a>,
+    pointer: Type,
+    width: WordWidth,
+    register_count: usize,
+    zero: Variable,
+    carry: Variable,
+    negative: Variable,
+    overflow: Variable,
+    fuel: Variable,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(
+        mut builder: FunctionBuilder<'a>,
+        pointer: Type,
+        width: WordWidth,
+        register_count: usize,
+    ) -> Compiler<'a> {
+        // Registers are variables 0 to register_count - 1, the rest follow.
+        let variable = |n: usize| Variable::from_u32((register_count + n) as u32);
+        for r in 0..register_count {
+            builder.declare_var(Variable::from_u32(r as u32), types::I64);
+        }
+        for n in 0..4 {
+            builder.declare_var(variable(n), types::I8);
+        }
+        builder.declare_var(variable(4), types::I64);
+        Compiler {
+            builder,
+            pointer,
+            width,
+            register_count,
+            zero: variable(0),
+            carry: variable(1),
+            negative: variable(2),
+            overflow: variable(3),
+            fuel: variable(4),
+        }
+    }
+
+    fn flag_offsets(&self) -> [(Variable, i32); 4] {
+        [
+            (self.zero, offset_of!(Context, zero) as i32),
+            (self.carry, offset_of!(Context, carry) as i32),
+            (self.negative, offset_of!(Context, negative) as i32),
+            (self.overflow, offset_of!(Context, overflow) as i32),
+        ]
+    }
+
+    fn compile(mut self, ops: &[Op]) {
+        let flags = MemFlags::trusted();
+        let entry = self.builder.create_block();
+        self.builder.append_block_params_for_function_params(entry);
+        // One block per address, plus one for the end of the program.
+        let blocks: Vec<Block> = (0..=ops.len())
+            .map(|_| self.builder.create_block())
+            .collect();
+        let exit = self.builder.create_block();
+        for ty in [types::I32, types::I64, types::I64] {
+            self.builder.append_block_param(exit, ty);
+        }
+
+        self.builder.switch_to_block(entry);
+        let context = self.builder.block_params(entry)[0];
+        let registers = self.load_field(context, offset_of!(Context, registers), self.pointer);
+        let memory = self.load_field(context, offset_of!(Context, memory), self.pointer);
+        let memory_len = self.load_field(context, offset_of!(Context, memory_len), types::I64);
+        for r in 0..self.register_count {
+            let value = self.builder.ins().uload32(flags, registers, 4 * r as i32);
+            self.builder.def_var(Variable::from_u32(r as u32), value);
+        }
+        for (variable, offset) in self.flag_offsets() {
+            let value = self.builder.ins().load(types::I8, flags, context, offset);
+            self.builder.def_var(variable, value);
+        }
+        let fuel = self.load_field(context, offset_of!(Context, fuel), types::I64);
+        self.builder.def_var(self.fuel, fuel);
+        let index = self.load_field(context, offset_of!(Context, index), types::I64);
+        let index = self.builder.ins().ireduce(types::I32, index);
+        let table: Vec<BlockCall> = blocks
+            .iter()
+            .map(|block| self.builder.func.dfg.block_call(*block, &[]))
+            .collect();
+        let end = self.builder.func.dfg.block_call(blocks[ops.len()], &[]);
+        let table = self
+            .builder
+            .create_jump_table(JumpTableData::new(end, &table));
+        self.builder.ins().br_table(index, table);
+
+        for (address, op) in ops.iter().enumerate() {
+            self.builder.switch_to_block(blocks[address]);
+            self.spend_fuel(address, exit);
+            self.compile_op(*op, address, &blocks, exit, memory, memory_len);
+        }
+
+        self.builder.switch_to_block(blocks[ops.len()]);
+        let code = self.builder.ins().iconst(types::I32, EXIT_END);
+        let address = self.builder.ins().iconst(types::I64, ops.len() as i64);
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        self.builder.ins().jump(exit, &[code, address, zero]);
+
+        // Writes everything back and returns the exit code.
+        self.builder.switch_to_block(exit);
+        let params = self.builder.block_params(exit).to_vec();
+        for r in 0..self.register_count {
+            let value = self.builder.use_var(Variable::from_u32(r as u32));
+            self.builder
+                .ins()
+                .istore32(flags, value, registers, 4 * r as i32);
+        }
+        for (variable, offset) in self.flag_offsets() {
+            let value = self.builder.use_var(variable);
+            self.builder.ins().store(flags, value, context, offset);
+        }
+        let fuel = self.builder.use_var(self.fuel);
+        self.builder
+            .ins()
+            .store(flags, fuel, context, offset_of!(Context, fuel) as i32);
+        self.builder
+            .ins()
+            .store(flags, params[1], context, offset_of!(Context, index) as i32);
+        self.builder.ins().store(
+            flags,
+            params[2],
+            context,
+            offset_of!(Context, address) as i32,
+        );
+        self.builder.ins().return_(&[params[0]]);
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    fn load_field(&mut self, context: Value, offset: usize, ty: Type) -> Value {
+        self.builder
+            .ins()
+            .load(ty, MemFlags::trusted(), context, offset as i32)
+    }
+
+    /// Leaves with `code` at `address` unless `ok` is true, then carries on in
+    /// a fresh block.
+    fn exit_unless(&mut self, ok: Value, code: i64, address: usize, aux: Value, exit: Block) {
+        let next = self.builder.create_block();
+        let code = self.builder.ins().iconst(types::I32, code);
+        let address = self.builder.ins().iconst(types::I64, address as i64);
+        self.builder
+            .ins()
+            .brif(ok, next, &[], exit, &[code, address, aux]);
+        self.builder.switch_to_block(next);
+    }
+
+    /// Takes one step of fuel, leaving first if there is none left.
+    fn spend_fuel(&mut self, address: usize, exit: Block) {
+        let fuel = self.builder.use_var(self.fuel);
+        let left = self.builder.ins().icmp_imm(IntCC::NotEqual, fuel, 0);
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        self.exit_unless(left, EXIT_OUT_OF_FUEL, address, zero, exit);
+        let fuel = self.builder.ins().iadd_imm(fuel, -1);
+        self.builder.def_var(self.fuel, fuel);
+    }
+
+    fn get(&mut self, register: u8) -> Value {
+        self.builder.use_var(Variable::from_u32(register as u32))
+    }
+
+    fn set(&mut self, register: u8, value: Value) {
+        self.builder
+            .def_var(Variable::from_u32(register as u32), value);
+    }
+
+    fn constant(&mut self, value: u32) -> Value {
+        self.builder.ins().iconst(types::I64, value as i64)
+    }
+
+    /// Sets Z and N from `res`, which is already reduced to a word.
+    fn set_result_flags(&mut self, res: Value) {
+        let zero = self.builder.ins().icmp_imm(IntCC::Equal, res, 0);
+        let sign = self
+            .builder
+            .ins()
+            .band_imm(res, self.width.sign_bit() as i64);
+        let negative = self.builder.ins().icmp_imm(IntCC::NotEqual, sign, 0);
+        self.builder.def_var(self.zero, zero);
+        self.builder.def_var(self.negative, negative);
+    }
+
+    fn logic(&mut self, d: u8, res: Value) {
+        self.set_result_flags(res);
+        let clear = self.builder.ins().iconst(types::I8, 0);
+        self.builder.def_var(self.carry, clear);
+        self.builder.def_var(self.overflow, clear);
+        self.set(d, res);
+    }
+
+    /// Same as `State::add_with_carry`. Leaves C alone if `keep_carry` is set.
+    fn add(&mut self, a: Value, b: Value, carry: Option<Value>, keep_carry: bool) -> Value {
+        let mut wide = self.builder.ins().iadd(a, b);
+        if let Some(carry) = carry {
+            let carry = self.builder.ins().uextend(types::I64, carry);
+            wide = self.builder.ins().iadd(wide, carry);
+        }
+        let res = self.builder.ins().band_imm(wide, self.width.mask() as i64);
+        self.set_result_flags(res);
+        if !keep_carry {
+            let carry = self.builder.ins().icmp_imm(
+                IntCC::UnsignedGreaterThan,
+                wide,
+                self.width.mask() as i64,
+            );
+            self.builder.def_var(self.carry, carry);
+        }
+        let x = self.builder.ins().bxor(a, res);
+        let y = self.builder.ins().bxor(b, res);
+        self.set_overflow(x, y);
+        res
+    }
+
+    /// Same as `State::sub_with_borrow`. Leaves C alone if `keep_carry` is set.
+    fn sub(&mut self, a: Value, b: Value, borrow: Option<Value>, keep_carry: bool) -> Value {
+        let mut wide = self.builder.ins().isub(a, b);
+        if let Some(borrow) = borrow {
+            let borrow = self.builder.ins().uextend(types::I64, borrow);
+            wide = self.builder.ins().isub(wide, borrow);
+        }
+        let res = self.builder.ins().band_imm(wide, self.width.mask() as i64);
+        self.set_result_flags(res);
+        if !keep_carry {
+            let carry = self.builder.ins().icmp_imm(IntCC::SignedLessThan, wide, 0);
+            self.builder.def_var(self.carry, carry);
+        }
+        let x = self.builder.ins().bxor(a, b);
+        let y = self.builder.ins().bxor(a, res);
+        self.set_overflow(x, y);
+        res
+    }
+
+    fn set_overflow(&mut self, x: Value, y: Value) {
+        let both = self.builder.ins().band(x, y);
+        let sign = self
+            .builder
+            .ins()
+            .band_imm(both, self.width.sign_bit() as i64);
+        let overflow = self.builder.ins().icmp_imm(IntCC::NotEqual, sign, 0);
+        self.builder.def_var(self.overflow, overflow);
+    }
+
+    /// Checks that `bytes` bytes at `address` are in memory and returns a
+    /// pointer to them.
+    fn memory_at(
+        &mut self,
+        address: Value,
+        bytes: usize,
+        at: usize,
+        exit: Block,
+        memory: Value,
+        memory_len: Value,
+    ) -> Value {
+        let end = self.builder.ins().iadd_imm(address, bytes as i64);
+        let ok = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedLessThanOrEqual, end, memory_len);
+        self.exit_unless(ok, EXIT_MEMORY, at, address, exit);
+        let offset = if self.pointer == types::I64 {
+            address
+        } else {
+            self.builder.ins().ireduce(self.pointer, address)
+        };
+        self.builder.ins().iadd(memory, offset)
+    }
+
+    fn load(&mut self, d: u8, a: u8, bytes: usize, access: (usize, Block, Value, Value)) {
+        let (at, exit, memory, memory_len) = access;
+        let address = self.get(a);
+        let pointer = self.memory_at(address, bytes, at, exit, memory, memory_len);
+        let flags = MemFlags::trusted().with_endianness(Endianness::Little);
+        let value = match bytes {
+            1 => self.builder.ins().uload8(types::I64, flags, pointer, 0),
+            2 => self.builder.ins().uload16(types::I64, flags, pointer, 0),
+            _ => self.builder.ins().uload32(flags, pointer, 0),
+        };
+        self.set(d, value);
+    }
+
+    fn store(&mut self, s: u8, a: u8, bytes: usize, access: (usize, Block, Value, Value)) {
+        let (at, exit, memory, memory_len) = access;
+        let address = self.get(a);
+        let pointer = self.memory_at(address, bytes, at, exit, memory, memory_len);
+        let flags = MemFlags::trusted().with_endianness(Endianness::Little);
+        let value = self.get(s);
+        match bytes {
+            1 => self.builder.ins().istore8(flags, value, pointer, 0),
+            2 => self.builder.ins().istore16(flags, value, pointer, 0),
+            _ => self.builder.ins().istore32(flags, value, pointer, 0),
+        };
+    }
+
+    fn branch(&mut self, condition: Value, target: u32, address: usize, blocks: &[Block]) {
+        self.builder.ins().brif(
+            condition,
+            blocks[target as usize],
+            &[],
+            blocks[address + 1],
+            &[],
+        );
+    }
+
+    fn flag(&mut self, variable: Variable) -> Value {
+        self.builder.use_var(variable)
+    }
+
+    fn compile_op(
+        &mut self,
+        op: Op,
+        address: usize,
+        blocks: &[Block],
+        exit: Block,
+        memory: Value,
+        memory_len: Value,
+    ) {
+        let mask = self.width.mask();
+        let access = (address, exit, memory, memory_len);
+        let bytes = self.width.bytes();
+        match op {
+            Op::Set(d, k) => {
+                let value = self.constant(k & mask);
+                self.set(d, value);
+            }
+            Op::Mov(d, a) => {
+                let value = self.get(a);
+                self.set(d, value);
+            }
+            Op::Add(d, a, b) | Op::Adc(d, a, b) => {
+                let carry = matches!(op, Op::Adc(..)).then(|| self.flag(self.carry));
+                let (a, b) = (self.get(a), self.get(b));
+                let res = self.add(a, b, carry, false);
+                self.set(d, res);
+            }
+            Op::Sub(d, a, b) | Op::Sbc(d, a, b) => {
+                let borrow = matches!(op, Op::Sbc(..)).then(|| self.flag(self.carry));
+                let (a, b) = (self.get(a), self.get(b));
+                let res = self.sub(a, b, borrow, false);
+                self.set(d, res);
+            }
+            Op::And(d, a, b) => {
+                let (a, b) = (self.get(a), self.get(b));
+                let res = self.builder.ins().band(a, b);
+                self.logic(d, res);
+            }
+            Op::Or(d, a, b) => {
+                let (a, b) = (self.get(a), self.get(b));
+                let res = self.builder.ins().bor(a, b);
+                self.logic(d, res);
+            }
+            Op::Xor(d, a, b) => {
+                let (a, b) = (self.get(a), self.get(b));
+                let res = self.builder.ins().bxor(a, b);
+                self.logic(d, res);
+            }
+            Op::Inc(d) => {
+                let (a, one) = (self.get(d), self.constant(1));
+                let res = self.add(a, one, None, true);
+                self.set(d, res);
+            }
+            Op::Dec(d) => {
+                let (a, one) = (self.get(d), self.constant(1));
+                let res = self.sub(a, one, None, true);
+                self.set(d, res);
+            }
+            Op::Not(d) => {
+                let a = self.get(d);
+                let res = self.builder.ins().bxor_imm(a, mask as i64);
+                self.logic(d, res);
+            }
+            Op::AddI(d, a, k) => {
+                let (a, k) = (self.get(a), self.constant(k));
+                let res = self.add(a, k, None, false);
+                self.set(d, res);
+            }
+            Op::SubI(d, a, k) => {
+                let (a, k) = (self.get(a), self.constant(k));
+                let res = self.sub(a, k, None, false);
+                self.set(d, res);
+            }
+            Op::AndI(d, a, k) => {
+                let a = self.get(a);
+                let res = self.builder.ins().band_imm(a, k as i64);
+                self.logic(d, res);
+            }
+            Op::OrI(d, a, k) => {
+                let a = self.get(a);
+                let res = self.builder.ins().bor_imm(a, k as i64);
+                self.logic(d, res);
+            }
+            Op::XorI(d, a, k) => {
+                let a = self.get(a);
+                let res = self.builder.ins().bxor_imm(a, k as i64);
+                self.logic(d, res);
+            }
+            Op::Cmp(a, b) => {
+                let (a, b) = (self.get(a), self.get(b));
+                self.sub(a, b, None, false);
+            }
+            Op::CmpI(a, k) => {
+                let (a, k) = (self.get(a), self.constant(k));
+                self.sub(a, k, None, false);
+            }
+            Op::Load(d, a) => self.load(d, a, bytes, access),
+            Op::LoadB(d, a) => self.load(d, a, 1, access),
+            Op::Store(s, a) => self.store(s, a, bytes, access),
+            Op::StoreB(s, a) => self.store(s, a, 1, access),
+            Op::J(target) => {
+                self.builder.ins().jump(blocks[target as usize], &[]);
+                return;
+            }
+            Op::Jz(t) | Op::Jnz(t) | Op::Jc(t) | Op::Jnc(t) | Op::Jn(t) | Op::Jv(t) => {
+                let (variable, when) = match op {
+                    Op::Jz(_) => (self.zero, true),
+                    Op::Jnz(_) => (self.zero, false),
+                    Op::Jc(_) => (self.carry, true),
+                    Op::Jnc(_) => (self.carry, false),
+                    Op::Jn(_) => (self.negative, true),
+                    _ => (self.overflow, true),
+                };
+                let flag = self.flag(variable);
+                let condition = if when {
+                    flag
+                } else {
+                    self.builder.ins().icmp_imm(IntCC::Equal, flag, 0)
+                };
+                self.branch(condition, t, address, blocks);
+                return;
+            }
+            Op::Jlt(t) | Op::Jge(t) => {
+                let cc = if matches!(op, Op::Jlt(_)) {
+                    IntCC::NotEqual
+                } else {
+                    IntCC::Equal
+                };
+                let (n, v) = (self.flag(self.negative), self.flag(self.overflow));
+                let condition = self.builder.ins().icmp(cc, n, v);
+                self.branch(condition, t, address, blocks);
+                return;
+            }
+            Op::Other => {
+                let code = self.builder.ins().iconst(types::I32, EXIT_INTERPRET);
+                let at = self.builder.ins().iconst(types::I64, address as i64);
+                let zero = self.builder.ins().iconst(types::I64, 0);
+                self.builder.ins().jump(exit, &[code, at, zero]);
+                return;
+            }
+        }
+        self.builder.ins().jump(blocks[address + 1], &[]);
+    }
+}
+
+impl State {
+    /// Runs `program` with its compiled code. Works like `run_bytecode`.
+    pub(crate) fn run_jit(
+        &mut self,
+        jit: &JitProgram,
+        program: &ResolvedProgram,
+        fuel: &mut u64,
+    ) -> Option<ErrorKind> {
+        loop {
+            let mut context = Context {
+                registers: self.registers.as_mut_ptr(),
+                memory: self.memory.as_mut_ptr(),
+                memory_len: self.memory.len() as u64,
+                index: self.index as u64,
+                fuel: *fuel,
+                address: 0,
+                zero: self.flags.zero as u8,
+                carry: self.flags.carry as u8,
+                negative: self.flags.negative as u8,
+                overflow: self.flags.overflow as u8,
+            };
+            // SAFETY: the code only touches the registers, the memory within
+            // `memory_len` and the context itself.
+            let exit = unsafe { (jit.entry)(&mut context) } as i64;
+            self.flags = Flags {
+                zero: context.zero != 0,
+                carry: context.carry != 0,
+                negative: context.negative != 0,
+                overflow: context.overflow != 0,
+            };
+            self.index = context.index as usize;
+            *fuel = context.fuel;
+            match exit {
+                EXIT_END => return Some(ErrorKind::EndOfProgram),
+                EXIT_MEMORY => {
+                    return Some(ErrorKind::MemoryOutOfBounds(context.address as usize))
+                }
+                EXIT_OUT_OF_FUEL => return None,
+                _ => {
+                    let instruction = &program.instructions()[self.index];
+                    if let Err(e) = self.intepret_instruction(instruction, program) {
+                        return Some(e);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,3 +1,24 @@
+pub mod bytecode;
+
+pub mod diagnostic;
+
+pub mod format;
+
+pub mod io;
+
+#[cfg(feature = "jit")]
+pub mod jit;
+
+pub mod linker;
+
+mod loop_detector;
+
+mod macros;
+
 pub mod machine;
 
 pub mod parser;
+
+pub mod program;
+
+pub mod word;
@@ -0,0 +1,378 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{Add, Neg, Range, Sub},
+    sync::Arc,
+};
+
+use crate::diagnostic::{Diagnostic, ParseError, Sources};
+use crate::machine::{Label, ProgramLine, Span};
+use crate::parser::sized;
+use crate::word::WordWidth;
+
+/// A file assembled on its own with `parse_module`, to be linked with others.
+/// Its labels are its own unless it exports them with `.global`.
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub name: Arc<str>,
+    pub lines: Vec<ProgramLine>,
+    /// The text of the file and of the files it includes, to show link
+    /// errors with.
+    pub(crate) sources: Sources,
+    /// The values in `lines` that were worked out from labels, and change
+    /// when the module is put after others.
+    pub(crate) relocations: Vec<Relocation>,
+}
+
+/// How a value worked out from labels changes when the linker moves the
+/// module it is in: it grows by `code` times the distance the code moves and
+/// by `data` times the distance the data moves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Moves {
+    pub(crate) code: i64,
+    pub(crate) data: i64,
+}
+
+impl Moves {
+    /// A value that stays the same wherever the module goes.
+    pub(crate) const ZERO: Moves = Moves { code: 0, data: 0 };
+    /// The address of a label in the code.
+    pub(crate) const CODE: Moves = Moves { code: 1, data: 0 };
+    /// The address of data in a section without an address of its own.
+    pub(crate) const DATA: Moves = Moves { code: 0, data: 1 };
+
+    pub(crate) fn times(self, factor: i64) -> Option<Moves> {
+        Some(Moves {
+            code: self.code.checked_mul(factor)?,
+            data: self.data.checked_mul(factor)?,
+        })
+    }
+}
+
+impl Add for Moves {
+    type Output = Moves;
+
+    fn add(self, other: Moves) -> Moves {
+        Moves {
+            code: self.code + other.code,
+            data: self.data + other.data,
+        }
+    }
+}
+
+impl Sub for Moves {
+    type Output = Moves;
+
+    fn sub(self, other: Moves) -> Moves {
+        Moves {
+            code: self.code - other.code,
+            data: self.data - other.data,
+        }
+    }
+}
+
+impl Neg for Moves {
+    type Output = Moves;
+
+    fn neg(self) -> Moves {
+        Moves {
+            code: -self.code,
+            data: -self.data,
+        }
+    }
+}
+
+/// Where a value that moves with its module is kept.
+#[derive(Debug, Clone)]
+pub(crate) enum Place {
+    /// The immediates of instructions, a word each, lowest word first.
+    Words(WordWidth),
+    /// The memory address of a `LOAD` or `STORE`.
+    Address,
+    /// The value at `offset` in the bytes of a `Data` line, stored like a
+    /// word of `width`.
+    Data { offset: usize, width: WordWidth },
+    /// Somewhere the value cannot change after assembly, like a shift
+    /// amount, which the text describes.
+    Fixed(&'static str),
+    /// The address of a `Data` or `DataLabel` line.
+    Line,
+}
+
+/// A value in a module that the linker has to change when it moves the
+/// module.
+#[derive(Debug, Clone)]
+pub(crate) struct Relocation {
+    /// The lines of the module the value is in.
+    pub(crate) lines: Range<usize>,
+    pub(crate) place: Place,
+    /// The value when the module is linked first.
+    pub(crate) value: i64,
+    /// How the value changes, or `None` when it uses labels in a way that
+    /// cannot be followed, like `label * label`.
+    pub(crate) moves: Option<Moves>,
+    /// The expression, as written.
+    pub(crate) expression: String,
+    pub(crate) span: Span,
+}
+
+impl Relocation {
+    /// Updates the value in `lines`, the lines of the module, for a module
+    /// whose code starts at address `code` and whose data starts at `data`.
+    fn apply(
+        &self,
+        lines: &mut [ProgramLine],
+        code: usize,
+        data: usize,
+        module: &str,
+    ) -> Result<(), Diagnostic> {
+        let error = |message: String| {
+            Err(Diagnostic {
+                span: self.span.clone(),
+                message,
+            })
+        };
+        let Some(moves) = self.moves else {
+            if code == 0 && data == 0 {
+                return Ok(());
+            }
+            return error(format!(
+                "{} cannot be worked out once {} is linked after other modules, only sums and differences of labels can",
+                self.expression, module
+            ));
+        };
+        let distance = moves.code * code as i64 + moves.data * data as i64;
+        if distance == 0 {
+            return Ok(());
+        }
+        let value = self.value + distance;
+        let too_large = |size: String| {
+            error(format!(
+                "{} is {} once {} is linked, which does not fit in {}",
+                self.expression, value, module, size
+            ))
+        };
+        match &self.place {
+            Place::Fixed(what) => error(format!(
+                "{} changes when {} is linked after other modules, so it cannot be {}",
+                self.expression, module, what
+            )),
+            Place::Words(width) => {
+                let words = self.lines.len() as u32;
+                let bits = width.bits() * words;
+                if bits < 64 && !(-(1i64 << (bits - 1))..(1i64 << bits)).contains(&value) {
+                    return match words {
+                        1 => too_large(sized(bits, "word")),
+                        _ => too_large(sized(bits, "number")),
+                    };
+                }
+                for (n, line) in lines[self.lines.clone()].iter_mut().enumerate() {
+                    if let ProgramLine::Ins(instruction, _) = line {
+                        let shift = (width.bits() * n as u32).min(63);
+                        *instruction.immediate_mut().unwrap() = width.wrap(value >> shift);
+                    }
+                }
+                Ok(())
+            }
+            Place::Address => {
+                let Ok(address) = u32::try_from(value) else {
+                    return error(format!(
+                        "{} is {} once {} is linked, which is not a valid memory address",
+                        self.expression, value, module
+                    ));
+                };
+                if let ProgramLine::Ins(instruction, _) = &mut lines[self.lines.start] {
+                    *instruction.immediate_mut().unwrap() = address;
+                }
+                Ok(())
+            }
+            Place::Data { offset, width } => {
+                if !width.fits(value) {
+                    return match width {
+                        WordWidth::W8 => too_large("a byte".to_string()),
+                        _ => too_large(sized(width.bits(), "word")),
+                    };
+                }
+                if let ProgramLine::Data(_, bytes, _) = &mut lines[self.lines.start] {
+                    let size = width.bytes();
+                    let value = width.wrap(value).to_le_bytes();
+                    bytes[*offset..offset + size].copy_from_slice(&value[..size]);
+                }
+                Ok(())
+            }
+            Place::Line => {
+                match &mut lines[self.lines.start] {
+                    ProgramLine::Data(address, ..) | ProgramLine::DataLabel(_, address, _) => {
+                        *address = value as usize
+                    }
+                    _ => {}
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Module {
+    /// The labels the module defines.
+    fn labels(&self) -> HashSet<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                ProgramLine::Lbl(Label(label), _) => Some(label.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Puts `modules` one after the other into a single program. A module that
+/// uses a label gets its own label of that name if it has one, and otherwise
+/// the one another module exports. Labels that are defined by more than one
+/// module are renamed to `label@module` in the modules that do not export
+/// them.
+///
+/// Fails if two modules export the same label, or if a module uses a label
+/// that neither it nor any export defines.
+///
+/// The data of each module goes after the data of the modules before it,
+/// except for sections with an address like `.data 0x80`, which stay where
+/// they are.
+///
+/// Values worked out from the labels of a module, like `SET R1, table + 2`,
+/// are moved along with it, so they mean the same as `LA`. That works for
+/// sums and differences of labels, also times a constant, but a module that
+/// uses labels in any other way, or as a shift amount or port, can only be
+/// linked first.
+pub fn link(modules: Vec<Module>) -> Result<Vec<ProgramLine>, ParseError> {
+    let mut diagnostics = Vec::new();
+    let defined: Vec<HashSet<&str>> = modules.iter().map(Module::labels).collect();
+    let mut definitions: HashMap<&str, usize> = HashMap::new();
+    for label in defined.iter().flatten() {
+        *definitions.entry(label).or_default() += 1;
+    }
+
+    // Which module exports each label, and where.
+    let mut exports: HashMap<&str, (usize, &Span)> = HashMap::new();
+    for (index, module) in modules.iter().enumerate() {
+        for line in &module.lines {
+            let ProgramLine::Global(Label(label), span) = line else {
+                continue;
+            };
+            match exports.get(label.as_str()) {
+                Some((first, _)) if *first == index => {}
+                Some((_, first)) => diagnostics.push(Diagnostic {
+                    span: span.clone(),
+                    message: format!("'{}' is already exported at {}", label, first),
+                }),
+                None => {
+                    exports.insert(label, (index, span));
+                }
+            }
+        }
+    }
+
+    let local_name = |index: usize, label: &str| {
+        let exported_here = exports.get(label).is_some_and(|(at, _)| *at == index);
+        if definitions[label] > 1 && !exported_here {
+            format!("{}@{}", label, modules[index].name)
+        } else {
+            label.to_string()
+        }
+    };
+    let undefined = |label: &str| match defined.iter().position(|labels| labels.contains(label)) {
+        Some(owner) => format!(
+            "'{}' is defined in {} but not exported, add '.global {}' there",
+            label, modules[owner].name, label
+        ),
+        None => format!("'{}' is not defined in any module", label),
+    };
+    let mut renamed = Vec::new();
+    // Where the code and the data of the module start in the linked program.
+    let mut code = 0;
+    let mut data = 0;
+    for (index, module) in modules.iter().enumerate() {
+        let start = renamed.len();
+        for line in &module.lines {
+            let mut line = line.clone();
+            match &mut line {
+                ProgramLine::Lbl(Label(label), _) => *label = local_name(index, label),
+                ProgramLine::Ins(instruction, span) => {
+                    if let Some(Label(label)) = instruction.label_mut() {
+                        if defined[index].contains(label.as_str()) {
+                            *label = local_name(index, label);
+                        } else if !exports.contains_key(label.as_str()) {
+                            diagnostics.push(Diagnostic {
+                                span: span.clone(),
+                                message: undefined(label),
+                            });
+                        }
+                    }
+                }
+                ProgramLine::Alias(..)
+                | ProgramLine::Global(..)
+                | ProgramLine::Data(..)
+                | ProgramLine::DataLabel(..) => {}
+            }
+            renamed.push(line);
+        }
+        for relocation in &module.relocations {
+            if let Err(diagnostic) =
+                relocation.apply(&mut renamed[start..], code, data, &module.name)
+            {
+                diagnostics.push(diagnostic);
+            }
+        }
+        code += module
+            .lines
+            .iter()
+            .filter(|line| matches!(line, ProgramLine::Ins(..)))
+            .count();
+        data = renamed
+            .iter()
+            .filter_map(|line| match line {
+                ProgramLine::Data(address, bytes, _) => Some(address + bytes.len()),
+                _ => None,
+            })
+            .fold(data, usize::max);
+    }
+
+    diagnostics.extend(overlapping_data(&renamed));
+    if diagnostics.is_empty() {
+        Ok(renamed)
+    } else {
+        let sources = modules
+            .iter()
+            .flat_map(|module| module.sources.clone())
+            .collect();
+        Err(ParseError {
+            diagnostics,
+            sources,
+        })
+    }
+}
+
+/// Reports every piece of data that goes where earlier data already is.
+pub(crate) fn overlapping_data(lines: &[ProgramLine]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut placed: Vec<(Range<usize>, &Span)> = Vec::new();
+    for line in lines {
+        let ProgramLine::Data(address, bytes, span) = line else {
+            continue;
+        };
+        let range = *address..address + bytes.len();
+        let overlap = placed
+            .iter()
+            .find(|(other, _)| range.start < other.end && other.start < range.end);
+        match overlap {
+            Some((other, at)) => diagnostics.push(Diagnostic {
+                span: span.clone(),
+                message: format!(
+                    "data at {}..{} overlaps the data at {}..{} from {}",
+                    range.start, range.end, other.start, other.end, at
+                ),
+            }),
+            None => placed.push((range, span)),
+        }
+    }
+    diagnostics
+}
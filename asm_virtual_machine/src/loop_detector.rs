@@ -0,0 +1,87 @@
+use crate::machine::{ErrorKind, Flags, State};
+
+/// Everything that decides what a program does next. Two equal snapshots
+/// taken at different times mean the program runs in a circle forever.
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    index: usize,
+    registers: Vec<u32>,
+    flags: Flags,
+    stack_pointer: usize,
+    call_stack: Vec<usize>,
+    memory: Vec<u8>,
+}
+
+impl Snapshot {
+    fn of(state: &State) -> Snapshot {
+        Snapshot {
+            index: state.index,
+            registers: state.registers[..state.register_count].to_vec(),
+            flags: state.flags,
+            stack_pointer: state.stack_pointer,
+            call_stack: state.call_stack.clone(),
+            memory: state.memory.clone(),
+        }
+    }
+
+    /// Compares the cheap parts first, since most steps differ in the index.
+    fn matches(&self, state: &State) -> bool {
+        self.index == state.index
+            && self.flags == state.flags
+            && self.registers[..] == state.registers[..state.register_count]
+            && self.stack_pointer == state.stack_pointer
+            && self.call_stack == state.call_stack
+            && self.memory == state.memory
+    }
+}
+
+/// Finds repeated machine states with Brent's algorithm: a snapshot is
+/// taken after 1, 2, 4, 8, ... steps and every state in between is compared
+/// with the last one. A loop with period `p` is found within about `3p`
+/// steps of entering it, at the cost of one comparison per step.
+///
+/// Only sound for programs that do not read input, since `IN` can make the
+/// same state continue differently.
+#[derive(Debug)]
+pub(crate) struct LoopDetector {
+    snapshot: Snapshot,
+    power: u64,
+    steps: u64,
+    /// Lowest and highest address run since the snapshot.
+    first: usize,
+    last: usize,
+}
+
+impl LoopDetector {
+    pub(crate) fn new(state: &State) -> LoopDetector {
+        LoopDetector {
+            snapshot: Snapshot::of(state),
+            power: 1,
+            steps: 0,
+            first: usize::MAX,
+            last: 0,
+        }
+    }
+
+    /// Records that the instruction at `address` was run and left the
+    /// machine in `state`.
+    pub(crate) fn observe(&mut self, address: usize, state: &State) -> Result<(), ErrorKind> {
+        self.steps += 1;
+        self.first = self.first.min(address);
+        self.last = self.last.max(address);
+        if self.snapshot.matches(state) {
+            return Err(ErrorKind::InfiniteLoop {
+                first: self.first,
+                last: self.last,
+                period: self.steps,
+            });
+        }
+        if self.steps == self.power {
+            *self = LoopDetector {
+                power: self.power * 2,
+                ..LoopDetector::new(state)
+            };
+        }
+        Ok(())
+    }
+}
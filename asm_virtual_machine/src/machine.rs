@@ -1,21 +1,35 @@
-use std::{
-    fmt::Display,
-    num::Wrapping,
-    ops::{BitAnd, BitOr, BitXor},
-};
-
-use strum::EnumString;
-
-#[derive(PartialEq, Eq, PartialOrd, Debug, Clone, EnumString, strum::Display)]
-pub enum Register {
-    R0,
-    R1,
-    R2,
-    R3,
-    R4,
-    R5,
-    R6,
-    R7,
+#[cfg(feature = "jit")]
+use std::cell::OnceCell;
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use crate::bytecode::Bytecode;
+use crate::io::IoDevice;
+#[cfg(feature = "jit")]
+use crate::jit::JitProgram;
+use crate::loop_detector::LoopDetector;
+use crate::program::ResolvedProgram;
+use crate::word::WordWidth;
+
+/// A register in the register file, `R0` is `Register(0)`.
+#[derive(PartialEq, Eq, PartialOrd, Debug, Clone, Copy, Hash)]
+pub struct Register(pub u8);
+
+impl Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "R{}", self.0)
+    }
+}
+
+impl TryFrom<&str> for Register {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value
+            .strip_prefix(['R', 'r'])
+            .and_then(|n| n.parse().ok())
+            .map(Register)
+            .ok_or_else(|| format!("'{}' is not a register", value))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -27,18 +41,128 @@ pub enum Instruction {
     Mov(Register, Register),
     Add(Register, Register, Register),
     Sub(Register, Register, Register),
+    Adc(Register, Register, Register),
+    Sbc(Register, Register, Register),
     Inc(Register),
     Dec(Register),
     And(Register, Register, Register),
     Or(Register, Register, Register),
     Xor(Register, Register, Register),
     Not(Register),
+    Mul(Register, Register, Register),
+    Mulh(Register, Register, Register),
+    Imul(Register, Register, Register),
+    Imulh(Register, Register, Register),
+    Div(Register, Register, Register),
+    Mod(Register, Register, Register),
+    Idiv(Register, Register, Register),
+    Imod(Register, Register, Register),
     Shl(Register, u8),
     Shr(Register, u8),
+    Sar(Register, u8),
+    Rol(Register, u8),
+    Ror(Register, u8),
+    ShlR(Register, Register),
+    ShrR(Register, Register),
+    SarR(Register, Register),
+    RolR(Register, Register),
+    RorR(Register, Register),
     Jz(Label),
     Jnz(Label),
     J(Label),
-    Set(Register, u8),
+    Jc(Label),
+    Jnc(Label),
+    Jn(Label),
+    Jv(Label),
+    Jlt(Label),
+    Jge(Label),
+    Jb(Label),
+    Jae(Label),
+    Set(Register, u32),
+    AddI(Register, Register, u32),
+    SubI(Register, Register, u32),
+    AndI(Register, Register, u32),
+    OrI(Register, Register, u32),
+    XorI(Register, Register, u32),
+    Cmp(Register, Register),
+    CmpI(Register, u32),
+    Load(Register, Register),
+    LoadI(Register, u32),
+    Store(Register, Register),
+    StoreI(Register, u32),
+    LoadB(Register, Register),
+    LoadBI(Register, u32),
+    StoreB(Register, Register),
+    StoreBI(Register, u32),
+    Push(Register),
+    Pop(Register),
+    In(Register, u8),
+    Out(u8, Register),
+    Call(Label),
+    Ret,
+    Halt,
+    HaltR(Register),
+    Jr(Register),
+    La(Register, Label),
+}
+
+impl Instruction {
+    /// The label this instruction refers to, if any.
+    pub fn label(&self) -> Option<&Label> {
+        match self {
+            Instruction::Jz(label)
+            | Instruction::Jnz(label)
+            | Instruction::J(label)
+            | Instruction::Jc(label)
+            | Instruction::Jnc(label)
+            | Instruction::Jn(label)
+            | Instruction::Jv(label)
+            | Instruction::Jlt(label)
+            | Instruction::Jge(label)
+            | Instruction::Jb(label)
+            | Instruction::Jae(label)
+            | Instruction::Call(label)
+            | Instruction::La(_, label) => Some(label),
+            _ => None,
+        }
+    }
+
+    pub fn label_mut(&mut self) -> Option<&mut Label> {
+        match self {
+            Instruction::Jz(label)
+            | Instruction::Jnz(label)
+            | Instruction::J(label)
+            | Instruction::Jc(label)
+            | Instruction::Jnc(label)
+            | Instruction::Jn(label)
+            | Instruction::Jv(label)
+            | Instruction::Jlt(label)
+            | Instruction::Jge(label)
+            | Instruction::Jb(label)
+            | Instruction::Jae(label)
+            | Instruction::Call(label)
+            | Instruction::La(_, label) => Some(label),
+            _ => None,
+        }
+    }
+
+    /// The word or memory address in the instruction, if it has one.
+    pub(crate) fn immediate_mut(&mut self) -> Option<&mut u32> {
+        match self {
+            Instruction::Set(_, k)
+            | Instruction::AddI(_, _, k)
+            | Instruction::SubI(_, _, k)
+            | Instruction::AndI(_, _, k)
+            | Instruction::OrI(_, _, k)
+            | Instruction::XorI(_, _, k)
+            | Instruction::CmpI(_, k)
+            | Instruction::LoadI(_, k)
+            | Instruction::StoreI(_, k)
+            | Instruction::LoadBI(_, k)
+            | Instruction::StoreBI(_, k) => Some(k),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Instruction {
@@ -52,6 +176,12 @@ impl Display for Instruction {
             Instruction::Sub(register, register1, register2) => {
                 write!(f, "SUB {}, {}, {}", register, register1, register2)
             }
+            Instruction::Adc(register, register1, register2) => {
+                write!(f, "ADC {}, {}, {}", register, register1, register2)
+            }
+            Instruction::Sbc(register, register1, register2) => {
+                write!(f, "SBC {}, {}, {}", register, register1, register2)
+            }
             Instruction::Inc(register) => write!(f, "INC {}", register),
             Instruction::Dec(register) => write!(f, "DEC {}", register),
             Instruction::And(register, register1, register2) => {
@@ -64,65 +194,481 @@ impl Display for Instruction {
                 write!(f, "XOR {}, {}, {}", register, register1, register2)
             }
             Instruction::Not(register) => write!(f, "NOT {}", register),
+            Instruction::Mul(register, register1, register2) => {
+                write!(f, "MUL {}, {}, {}", register, register1, register2)
+            }
+            Instruction::Mulh(register, register1, register2) => {
+                write!(f, "MULH {}, {}, {}", register, register1, register2)
+            }
+            Instruction::Imul(register, register1, register2) => {
+                write!(f, "IMUL {}, {}, {}", register, register1, register2)
+            }
+            Instruction::Imulh(register, register1, register2) => {
+                write!(f, "IMULH {}, {}, {}", register, register1, register2)
+            }
+            Instruction::Div(register, register1, register2) => {
+                write!(f, "DIV {}, {}, {}", register, register1, register2)
+            }
+            Instruction::Mod(register, register1, register2) => {
+                write!(f, "MOD {}, {}, {}", register, register1, register2)
+            }
+            Instruction::Idiv(register, register1, register2) => {
+                write!(f, "IDIV {}, {}, {}", register, register1, register2)
+            }
+            Instruction::Imod(register, register1, register2) => {
+                write!(f, "IMOD {}, {}, {}", register, register1, register2)
+            }
             Instruction::Shl(register, k) => write!(f, "SHL {}, {}", register, k),
             Instruction::Shr(register, k) => write!(f, "SHR {}, {}", register, k),
+            Instruction::Sar(register, k) => write!(f, "SAR {}, {}", register, k),
+            Instruction::Rol(register, k) => write!(f, "ROL {}, {}", register, k),
+            Instruction::Ror(register, k) => write!(f, "ROR {}, {}", register, k),
+            Instruction::ShlR(register, amount) => write!(f, "SHL {}, {}", register, amount),
+            Instruction::ShrR(register, amount) => write!(f, "SHR {}, {}", register, amount),
+            Instruction::SarR(register, amount) => write!(f, "SAR {}, {}", register, amount),
+            Instruction::RolR(register, amount) => write!(f, "ROL {}, {}", register, amount),
+            Instruction::RorR(register, amount) => write!(f, "ROR {}, {}", register, amount),
             Instruction::Jz(label) => write!(f, "JZ {}", label.0),
             Instruction::Jnz(label) => write!(f, "JNZ {}", label.0),
             Instruction::J(label) => write!(f, "J {}", label.0),
+            Instruction::Jc(label) => write!(f, "JC {}", label.0),
+            Instruction::Jnc(label) => write!(f, "JNC {}", label.0),
+            Instruction::Jn(label) => write!(f, "JN {}", label.0),
+            Instruction::Jv(label) => write!(f, "JV {}", label.0),
+            Instruction::Jlt(label) => write!(f, "JLT {}", label.0),
+            Instruction::Jge(label) => write!(f, "JGE {}", label.0),
+            Instruction::Jb(label) => write!(f, "JB {}", label.0),
+            Instruction::Jae(label) => write!(f, "JAE {}", label.0),
             Instruction::Set(register, k) => write!(f, "SET {}, {}", register, k),
+            Instruction::AddI(register, register1, k) => {
+                write!(f, "ADDI {}, {}, {}", register, register1, k)
+            }
+            Instruction::SubI(register, register1, k) => {
+                write!(f, "SUBI {}, {}, {}", register, register1, k)
+            }
+            Instruction::AndI(register, register1, k) => {
+                write!(f, "ANDI {}, {}, {}", register, register1, k)
+            }
+            Instruction::OrI(register, register1, k) => {
+                write!(f, "ORI {}, {}, {}", register, register1, k)
+            }
+            Instruction::XorI(register, register1, k) => {
+                write!(f, "XORI {}, {}, {}", register, register1, k)
+            }
+            Instruction::Cmp(register, register1) => write!(f, "CMP {}, {}", register, register1),
+            Instruction::CmpI(register, k) => write!(f, "CMPI {}, {}", register, k),
+            Instruction::Load(register, address) => write!(f, "LOAD {}, [{}]", register, address),
+            Instruction::LoadI(register, address) => write!(f, "LOAD {}, [{}]", register, address),
+            Instruction::Store(register, address) => {
+                write!(f, "STORE {}, [{}]", register, address)
+            }
+            Instruction::StoreI(register, address) => {
+                write!(f, "STORE {}, [{}]", register, address)
+            }
+            Instruction::LoadB(register, address) => {
+                write!(f, "LOADB {}, [{}]", register, address)
+            }
+            Instruction::LoadBI(register, address) => {
+                write!(f, "LOADB {}, [{}]", register, address)
+            }
+            Instruction::StoreB(register, address) => {
+                write!(f, "STOREB {}, [{}]", register, address)
+            }
+            Instruction::StoreBI(register, address) => {
+                write!(f, "STOREB {}, [{}]", register, address)
+            }
+            Instruction::Push(register) => write!(f, "PUSH {}", register),
+            Instruction::Pop(register) => write!(f, "POP {}", register),
+            Instruction::In(register, port) => write!(f, "IN {}, {}", register, port),
+            Instruction::Out(port, register) => write!(f, "OUT {}, {}", port, register),
+            Instruction::Call(label) => write!(f, "CALL {}", label.0),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Halt => write!(f, "HALT"),
+            Instruction::HaltR(register) => write!(f, "HALT {}", register),
+            Instruction::Jr(register) => write!(f, "JR {}", register),
+            Instruction::La(register, label) => write!(f, "LA {}, {}", register, label.0),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+enum ShiftKind {
+    Shl,
+    Shr,
+    Sar,
+    Rol,
+    Ror,
+}
+
+/// The status register. Arithmetic follows the usual two's complement rules;
+/// after a subtraction `carry` holds the borrow, so `JB` means "unsigned less than".
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub zero: bool,
+    pub carry: bool,
+    pub negative: bool,
+    pub overflow: bool,
+}
+
+impl Display for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Z={} C={} N={} V={}",
+            self.zero as u8, self.carry as u8, self.negative as u8, self.overflow as u8
+        )
+    }
+}
+
+/// Where a piece of a program is in its source. Lines and columns count
+/// from 1, and `end_column` is just past the last character.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Span {
+    /// Name of the file, if the program was read from one.
+    pub file: Option<Arc<str>>,
+    pub line: usize,
+    pub column: usize,
+    pub end_column: usize,
+    /// The macro call this came from. The line and columns are then those in
+    /// the definition of the macro.
+    pub expansion: Option<Arc<Expansion>>,
+}
+
+impl Display for Span {
+    /// Written like `main.asm:12:5`, or `line 12` without a file, followed by
+    /// the macro calls it was expanded from.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.line, self.column)?,
+            None => write!(f, "line {}", self.line)?,
+        }
+        match &self.expansion {
+            Some(expansion) => write!(
+                f,
+                " in macro {} called at {}",
+                expansion.name, expansion.call
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A call of a macro, which the lines of its body were copied from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    /// Name of the macro, as written in its `.macro` line.
+    pub name: String,
+    pub call: Span,
+}
+
 #[derive(Debug, Clone)]
 pub enum ProgramLine {
-    Ins(Instruction),
-    Lbl(Label),
+    Ins(Instruction, Span),
+    Lbl(Label, Span),
+    /// `.alias name Rn`, only used to name the register in dumps.
+    Alias(String, Register, Span),
+    /// `.global label`, makes the label visible to the other modules when
+    /// they are linked.
+    Global(Label, Span),
+    /// Bytes that `Machine::init_program` puts in memory at the address, from
+    /// `.byte`, `.word`, `.string` or `.space`.
+    Data(usize, Vec<u8>, Span),
+    /// A label in the `.data` section, with the address it stands for.
+    DataLabel(Label, usize, Span),
+}
+
+impl ProgramLine {
+    pub fn span(&self) -> &Span {
+        match self {
+            ProgramLine::Ins(_, span)
+            | ProgramLine::Lbl(_, span)
+            | ProgramLine::Alias(_, _, span)
+            | ProgramLine::Global(_, span)
+            | ProgramLine::Data(_, _, span)
+            | ProgramLine::DataLabel(_, _, span) => span,
+        }
+    }
+}
+
+/// Settings that are fixed for the lifetime of a `Machine`.
+#[derive(Debug, Clone)]
+pub struct MachineConfig {
+    pub word_width: WordWidth,
+    /// Number of general purpose registers, at most 256.
+    pub register_count: usize,
+    /// Size of the data memory in bytes.
+    pub memory_size: usize,
+    /// Number of bytes at the top of memory reserved for `PUSH`/`POP`.
+    pub stack_size: usize,
+    /// Maximum number of nested `CALL`s.
+    pub call_depth: usize,
+    /// Number of steps a program may take before it is stopped with
+    /// `ErrorKind::StepLimitExceeded`, or `None` for no limit.
+    pub step_limit: Option<u64>,
+    /// Stop programs that provably run forever with
+    /// `ErrorKind::InfiniteLoop`. Makes `run` as slow as `step`, and is
+    /// ignored for programs that use `IN`.
+    pub detect_loops: bool,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            word_width: WordWidth::default(),
+            register_count: 8,
+            memory_size: 256,
+            stack_size: 64,
+            call_depth: 64,
+            step_limit: None,
+            detect_loops: false,
+        }
+    }
 }
 
+/// Largest register file a machine can have.
+pub const MAX_REGISTERS: usize = 256;
+
 pub struct Machine {
-    registers: Vec<(Register, Wrapping<u8>)>,
-    flag: bool,
-    program: Vec<ProgramLine>,
-    index: usize,
+    state: State,
+    program: ResolvedProgram,
+    code: Bytecode,
+    /// Native code for `code`, compiled on the first `run`. Holds `None` if
+    /// compiling failed, in which case the bytecode is used.
+    #[cfg(feature = "jit")]
+    jit: OnceCell<Option<JitProgram>>,
+    step_limit: Option<u64>,
+    /// Steps taken since the program was loaded.
+    steps: u64,
+    detect_loops: bool,
+    loop_detector: Option<LoopDetector>,
+}
+
+/// Everything a running program can change. Kept apart from the program so
+/// that an instruction can be executed while it is borrowed from the program.
+pub(crate) struct State {
+    pub(crate) width: WordWidth,
+    pub(crate) registers: [u32; MAX_REGISTERS],
+    pub(crate) register_count: usize,
+    pub(crate) flags: Flags,
+    pub(crate) memory: Vec<u8>,
+    pub(crate) stack_pointer: usize,
+    stack_limit: usize,
+    /// Addresses of the `CALL`s that are waiting for a `RET`.
+    pub(crate) call_stack: Vec<usize>,
+    call_depth: usize,
+    devices: HashMap<u8, Box<dyn IoDevice>>,
+    /// Address of the next instruction.
+    pub(crate) index: usize,
+}
+
+/// What went wrong, without saying where.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    EndOfProgram,
+    /// The program executed `HALT` with the given exit code.
+    Halted(u32),
+    /// A jump to a label that is not defined anywhere.
+    MissingLabel(String),
+    /// A label that is defined more than once, so jumps to it are ambiguous.
+    DuplicateLabel(String),
+    /// `Machine::set_register` with a register the machine does not have.
+    InvalidRegister(usize),
+    MemoryOutOfBounds(usize),
+    /// Division or modulo by zero at the given address.
+    DivideByZero(usize),
+    StackOverflow,
+    StackUnderflow,
+    /// `CALL` with as many calls open as the machine allows, which is given.
+    CallStackOverflow(usize),
+    EmptyCallStack,
+    /// `IN` or `OUT` on a port without a device.
+    NoDevice(u8),
+    /// The device on the port had nothing to read.
+    NoInput(u8),
+    /// `JR` to an address past the end of the program.
+    InvalidJumpTarget(u32),
+    /// `LA` of a label whose address does not fit in a register.
+    AddressOutOfRange(usize),
+    /// The program used up its step budget, which is given.
+    StepLimitExceeded(u64),
+    /// The machine came back to a state it was in before, so the program
+    /// would run forever. `first` and `last` are the lowest and highest
+    /// address in the loop and `period` its length in steps.
+    InfiniteLoop {
+        first: usize,
+        last: usize,
+        period: u64,
+    },
 }
 
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::EndOfProgram => write!(f, "ran past the end of the program"),
+            ErrorKind::Halted(code) => write!(f, "halted with exit code {}", code),
+            ErrorKind::MissingLabel(label) => write!(f, "label '{}' is not defined", label),
+            ErrorKind::DuplicateLabel(label) => {
+                write!(f, "label '{}' is defined more than once", label)
+            }
+            ErrorKind::InvalidRegister(n) => write!(f, "register R{} does not exist", n),
+            ErrorKind::MemoryOutOfBounds(address) => {
+                write!(f, "memory address {} is out of bounds", address)
+            }
+            ErrorKind::DivideByZero(_) => write!(f, "division by zero"),
+            ErrorKind::StackOverflow => write!(f, "stack overflow"),
+            ErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            ErrorKind::CallStackOverflow(depth) => {
+                write!(f, "call depth of {} exceeded", depth)
+            }
+            ErrorKind::EmptyCallStack => write!(f, "RET without a matching CALL"),
+            ErrorKind::NoDevice(port) => write!(f, "no device on port {}", port),
+            ErrorKind::NoInput(port) => write!(f, "no input left on port {}", port),
+            ErrorKind::InvalidJumpTarget(target) => {
+                write!(f, "jump target {} is outside the program", target)
+            }
+            ErrorKind::AddressOutOfRange(address) => {
+                write!(f, "address {} does not fit in a register", address)
+            }
+            ErrorKind::StepLimitExceeded(limit) => {
+                write!(f, "step limit of {} steps exceeded", limit)
+            }
+            ErrorKind::InfiniteLoop {
+                first,
+                last,
+                period,
+            } => write!(
+                f,
+                "infinite loop between addresses {:04} and {:04}, repeating every {} steps",
+                first, last, period
+            ),
+        }
+    }
+}
+
+/// The instruction an error happened at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub address: usize,
+    pub span: Span,
+    /// The instruction as text, like `JZ loop2`.
+    pub instruction: String,
+}
+
+/// An error together with the place in the program it happened at, if there
+/// is one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramError {
+    pub kind: ErrorKind,
+    pub location: Option<Location>,
+}
+
+impl ProgramError {
+    /// An error that does not belong to any instruction.
+    pub fn new(kind: ErrorKind) -> ProgramError {
+        ProgramError {
+            kind,
+            location: None,
+        }
+    }
+}
+
+impl Display for ProgramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(
+                f,
+                "{}: {}: {}",
+                location.span, location.instruction, self.kind
+            ),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for ProgramError {}
+
+/// How a run of a program ended.
 #[derive(Debug, PartialEq)]
-pub enum ProgramError {
+pub enum RunOutcome {
+    /// Stopped at a `HALT` with the given exit code.
+    Halted(u32),
+    /// Ran past the last instruction.
     EndOfProgram,
-    MissingLabel,
-    InvalidRegister,
+    /// Stopped by a runtime error.
+    Error(ProgramError),
 }
 
-impl Machine {
-    fn init_registers() -> Vec<(Register, Wrapping<u8>)> {
-        vec![
-            (Register::R0, Wrapping(0)),
-            (Register::R1, Wrapping(0)),
-            (Register::R2, Wrapping(0)),
-            (Register::R3, Wrapping(0)),
-            (Register::R4, Wrapping(0)),
-            (Register::R5, Wrapping(0)),
-            (Register::R6, Wrapping(0)),
-            (Register::R7, Wrapping(0)),
-        ]
+impl RunOutcome {
+    /// Process exit code for the CLI: the `HALT` code (modulo 256), 0 for
+    /// falling off the end and 70 (`EX_SOFTWARE`) for runtime errors. A
+    /// program can halt with 70 as well, so the code alone does not say
+    /// whether a run failed.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            RunOutcome::Halted(code) => *code as u8,
+            RunOutcome::EndOfProgram => 0,
+            RunOutcome::Error(_) => 70,
+        }
+    }
+}
+
+impl From<ProgramError> for RunOutcome {
+    fn from(error: ProgramError) -> Self {
+        match error.kind {
+            ErrorKind::Halted(code) => RunOutcome::Halted(code),
+            ErrorKind::EndOfProgram => RunOutcome::EndOfProgram,
+            _ => RunOutcome::Error(error),
+        }
+    }
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
     }
+}
 
+impl Machine {
     pub fn new() -> Machine {
+        Machine::with_config(MachineConfig::default())
+    }
+
+    pub fn with_config(config: MachineConfig) -> Machine {
         Machine {
-            registers: Machine::init_registers(),
-            flag: false,
-            program: Vec::new(),
-            index: 0,
+            state: State {
+                width: config.word_width,
+                registers: [0; MAX_REGISTERS],
+                register_count: config.register_count.min(MAX_REGISTERS),
+                flags: Flags::default(),
+                memory: vec![0; config.memory_size],
+                stack_pointer: config.memory_size,
+                stack_limit: config.memory_size.saturating_sub(config.stack_size),
+                call_stack: Vec::new(),
+                call_depth: config.call_depth,
+                devices: HashMap::new(),
+                index: 0,
+            },
+            program: ResolvedProgram::default(),
+            code: Bytecode::default(),
+            #[cfg(feature = "jit")]
+            jit: OnceCell::new(),
+            step_limit: config.step_limit,
+            steps: 0,
+            detect_loops: config.detect_loops,
+            loop_detector: None,
         }
     }
 
     pub fn get_current_instruction(&self) -> String {
-        let current_instruction = self.program.get(self.index);
+        let current_instruction = self.program.instructions().get(self.state.index);
         match current_instruction {
-            None => format!("End of program"),
-            Some(ProgramLine::Ins(i)) => format!("At instruction: {}", i),
-            Some(ProgramLine::Lbl(l)) => format!("At label: {:?}", l),
+            None => "End of program".to_string(),
+            Some(i) => format!(
+                "At instruction {:04} ({}): {}",
+                self.state.index,
+                self.program.span(self.state.index),
+                i
+            ),
         }
     }
 
@@ -132,178 +678,769 @@ impl Machine {
     }
 
     pub fn get_string_registers(&self) -> String {
+        let state = &self.state;
         let mut result = String::new();
-        for (r, v) in self.registers.iter() {
-            let signed = v.0 as i8;
+        let bits = state.width.bits() as usize;
+        let digits = state.width.decimal_digits();
+        for (r, v) in state.registers[..state.register_count].iter().enumerate() {
+            let r = Register(r as u8);
+            let signed = state.width.to_signed(*v);
+            let name = match self.program.aliases().get(&r) {
+                Some(aliases) => format!("{} ({})", r, aliases.join(", ")),
+                None => r.to_string(),
+            };
             result = format!(
-                "{}Register: {:?} = binary: {:08b} unsigned: {:03} signed: {:03}\t\n",
-                result, r, v, v, signed
+                "{}Register: {} = binary: {:0bits$b} unsigned: {:0digits$} signed: {:0digits$}\t\n",
+                result,
+                name,
+                v,
+                v,
+                signed,
+                bits = bits,
+                digits = digits
             );
         }
-        result
+        let return_addresses: Vec<String> = state
+            .call_stack
+            .iter()
+            .map(|address| format!("{:04}", address + 1))
+            .collect();
+        format!(
+            "{}Flags: {}\nStack pointer: {}\tCall depth: {}\tReturn addresses: [{}]\n",
+            result,
+            state.flags,
+            state.stack_pointer,
+            state.call_stack.len(),
+            return_addresses.join(", ")
+        )
     }
 
     pub fn print_registers(&self) {
         print!("{}", self.get_string_registers());
     }
 
-    pub fn init_program(&mut self, program: Vec<ProgramLine>) {
-        self.program = program;
-        self.index = 0;
+    pub fn get_string_memory(&self) -> String {
+        let mut result = String::new();
+        let mut labels = self.program.data_labels().iter().peekable();
+        for (row, bytes) in self.state.memory.chunks(16).enumerate() {
+            let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            result = format!("{}{:04x}: {}", result, row * 16, bytes.join(" "));
+            // The data labels that point into this row.
+            let mut names = Vec::new();
+            while let Some((address, label)) =
+                labels.next_if(|(address, _)| *address < row * 16 + 16)
+            {
+                names.push(format!("{} at {:04x}", label, address));
+            }
+            if !names.is_empty() {
+                result = format!("{}  ; {}", result, names.join(", "));
+            }
+            result.push('\n');
+        }
+        result
     }
 
-    fn get_register(&self, r: &Register) -> Wrapping<u8> {
-        let res = self
-            .registers
+    pub fn print_memory(&self) {
+        print!("{}", self.get_string_memory());
+    }
+
+    /// Current value of register `r`.
+    pub fn register(&self, r: &Register) -> u32 {
+        self.state.get_register(r)
+    }
+
+    /// The data memory, with the stack at its end.
+    pub fn memory(&self) -> &[u8] {
+        &self.state.memory
+    }
+
+    /// Current status flags.
+    pub fn flags(&self) -> Flags {
+        self.state.flags
+    }
+
+    /// Connects `device` to `port`, replacing any device already there.
+    pub fn attach_device(&mut self, port: u8, device: Box<dyn IoDevice>) {
+        self.state.devices.insert(port, device);
+    }
+
+    pub fn detach_device(&mut self, port: u8) -> Option<Box<dyn IoDevice>> {
+        self.state.devices.remove(&port)
+    }
+
+    /// Resolves the labels of `program`, puts its data in memory and gets
+    /// ready to run it from the first instruction. Fails if a label is
+    /// defined twice, a jump refers to a label that is not defined or the
+    /// data does not fit in memory, in which case the previous program stays
+    /// loaded.
+    pub fn init_program(&mut self, program: Vec<ProgramLine>) -> Result<(), ProgramError> {
+        let program = ResolvedProgram::resolve(program)?;
+        for (address, bytes) in program.data() {
+            let end = address + bytes.len();
+            if end > self.state.memory.len() {
+                let outside = (*address).max(self.state.memory.len());
+                return Err(ProgramError::new(ErrorKind::MemoryOutOfBounds(outside)));
+            }
+        }
+        for (address, bytes) in program.data() {
+            self.state.memory[*address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        self.program = program;
+        self.code = Bytecode::lower(&self.program);
+        #[cfg(feature = "jit")]
+        {
+            self.jit = OnceCell::new();
+        }
+        self.state.index = 0;
+        self.state.stack_pointer = self.state.memory.len();
+        self.state.call_stack.clear();
+        self.steps = 0;
+        let reads_input = self
+            .program
+            .instructions()
             .iter()
-            .filter(|re| re.0 == *r)
-            .nth(0)
-            .unwrap();
-        res.1
-    }
-
-    fn modify_register(&mut self, r: &Register, value: Wrapping<u8>) {
-        let reg_pos = self.registers.iter().position(|re| re.0 == *r).unwrap();
-        let reg = self.registers.get_mut(reg_pos).unwrap();
-        reg.1 = value;
-    }
-
-    pub fn set_register(&mut self, rn: usize, value: i8) -> Result<(), ProgramError> {
-        let r = match rn {
-            0 => Register::R0,
-            1 => Register::R1,
-            2 => Register::R2,
-            3 => Register::R3,
-            4 => Register::R4,
-            5 => Register::R5,
-            6 => Register::R6,
-            7 => Register::R7,
-            _ => return Err(ProgramError::InvalidRegister),
-        };
-        self.modify_register(&r, Wrapping(value as u8));
+            .any(|instruction| matches!(instruction, Instruction::In(..)));
+        self.loop_detector =
+            (self.detect_loops && !reads_input).then(|| LoopDetector::new(&self.state));
+        Ok(())
+    }
+
+    pub fn program(&self) -> &ResolvedProgram {
+        &self.program
+    }
+
+    pub fn word_width(&self) -> WordWidth {
+        self.state.width
+    }
+
+    pub fn register_count(&self) -> usize {
+        self.state.register_count
+    }
+
+    /// Sets register `rn`. Negative values are stored in two's complement.
+    pub fn set_register(&mut self, rn: usize, value: i64) -> Result<(), ProgramError> {
+        if rn >= self.state.register_count {
+            return Err(ProgramError::new(ErrorKind::InvalidRegister(rn)));
+        }
+        let value = self.state.width.wrap(value);
+        self.state.modify_register(&Register(rn as u8), value);
 
         Ok(())
     }
 
-    fn goto_label(&mut self, lbl: &Label) -> Result<(), ProgramError> {
-        let Some(label_pos) = self.program.iter().position(|pl| match pl {
-            ProgramLine::Ins(_) => false,
-            ProgramLine::Lbl(label) => label.0.eq(&lbl.0),
-        }) else {
-            return Err(ProgramError::MissingLabel);
+    /// Number of instructions in the loaded program. Valid addresses run from
+    /// 0 up to and including this, the last one being the end of the program.
+    pub fn instruction_count(&self) -> usize {
+        self.program.len()
+    }
+
+    /// Address of the instruction that runs next.
+    pub fn current_address(&self) -> usize {
+        self.state.index
+    }
+
+    /// Where the instruction that runs next is in the source, or `None` at
+    /// the end of the program.
+    pub fn current_span(&self) -> Option<&Span> {
+        (self.state.index < self.program.len()).then(|| self.program.span(self.state.index))
+    }
+
+    /// Runs until the program halts, falls off the end or fails. Ends in the
+    /// same state as calling `step` until it fails, only faster.
+    pub fn run(&mut self) -> RunOutcome {
+        loop {
+            if let Some(outcome) = self.run_steps(u64::MAX) {
+                return outcome;
+            }
+        }
+    }
+
+    /// Like `run`, but stops after `steps` calls' worth of `step`. Returns
+    /// `None` if the program is still running by then; calling it again
+    /// carries on where it stopped.
+    pub fn run_steps(&mut self, steps: u64) -> Option<RunOutcome> {
+        if self.loop_detector.is_some() {
+            return (0..steps)
+                .find_map(|_| self.step().err())
+                .map(RunOutcome::from);
+        }
+        let budget = match self.step_limit {
+            Some(limit) => limit.saturating_sub(self.steps),
+            None => u64::MAX,
+        };
+        let allowed = steps.min(budget);
+        let mut fuel = allowed;
+        let stopped = self.run_engine(&mut fuel);
+        self.steps += allowed - fuel;
+        match (stopped, self.step_limit) {
+            (Some(kind), _) => Some(self.error_here(kind).into()),
+            // Out of budget rather than out of `steps`.
+            (None, Some(limit)) if steps > budget => {
+                Some(self.error_here(ErrorKind::StepLimitExceeded(limit)).into())
+            }
+            (None, _) => None,
+        }
+    }
+
+    /// Runs the fastest engine available for at most `fuel` steps.
+    fn run_engine(&mut self, fuel: &mut u64) -> Option<ErrorKind> {
+        #[cfg(feature = "jit")]
+        if let Some(jit) = self.jit.get_or_init(|| {
+            JitProgram::compile(&self.code, self.state.width, self.state.register_count)
+        }) {
+            return self.state.run_jit(jit, &self.program, fuel);
+        }
+        self.state.run_bytecode(&self.code, &self.program, fuel)
+    }
+
+    /// Steps taken since the program was loaded.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn bytecode(&self) -> &Bytecode {
+        &self.code
+    }
+
+    pub fn step(&mut self) -> Result<(), ProgramError> {
+        if let Some(limit) = self.step_limit.filter(|limit| self.steps >= *limit) {
+            return Err(self.error_here(ErrorKind::StepLimitExceeded(limit)));
+        }
+        let address = self.state.index;
+        let Some(instruction) = self.program.instructions().get(address) else {
+            return Err(ProgramError::new(ErrorKind::EndOfProgram));
         };
+        self.steps += 1;
+        let mut result = self.state.intepret_instruction(instruction, &self.program);
+        if let (Ok(()), Some(detector)) = (&result, &mut self.loop_detector) {
+            result = detector.observe(address, &self.state);
+        }
+        result.map_err(|kind| self.error_here(kind))
+    }
+
+    /// Attaches the current instruction to `kind`. Errors leave the index on
+    /// the instruction that caused them.
+    fn error_here(&self, kind: ErrorKind) -> ProgramError {
+        let address = self.state.index;
+        let location = self
+            .program
+            .instructions()
+            .get(address)
+            .map(|instruction| Location {
+                address,
+                span: self.program.span(address).clone(),
+                instruction: instruction.to_string(),
+            });
+        ProgramError { kind, location }
+    }
+}
+
+impl State {
+    fn get_register(&self, r: &Register) -> u32 {
+        self.registers[r.0 as usize]
+    }
+
+    pub(crate) fn modify_register(&mut self, r: &Register, value: u32) {
+        self.registers[r.0 as usize] = value & self.width.mask();
+    }
 
-        self.index = label_pos;
+    /// Reads `bytes` bytes starting at `address` as a little endian number.
+    pub(crate) fn read_memory(&self, address: usize, bytes: usize) -> Result<u32, ErrorKind> {
+        let cells = self
+            .memory
+            .get(address..address + bytes)
+            .ok_or(ErrorKind::MemoryOutOfBounds(address))?;
+        Ok(cells
+            .iter()
+            .rev()
+            .fold(0, |value, cell| (value << 8) | *cell as u32))
+    }
+
+    pub(crate) fn write_memory(
+        &mut self,
+        address: usize,
+        bytes: usize,
+        value: u32,
+    ) -> Result<(), ErrorKind> {
+        let cells = self
+            .memory
+            .get_mut(address..address + bytes)
+            .ok_or(ErrorKind::MemoryOutOfBounds(address))?;
+        for (i, cell) in cells.iter_mut().enumerate() {
+            *cell = (value >> (8 * i)) as u8;
+        }
         Ok(())
     }
 
-    fn set_flag(&mut self, value: bool) {
-        self.flag = value;
+    fn push(&mut self, value: u32) -> Result<(), ErrorKind> {
+        let bytes = self.width.bytes();
+        if self.stack_pointer < self.stack_limit + bytes {
+            return Err(ErrorKind::StackOverflow);
+        }
+        self.stack_pointer -= bytes;
+        self.write_memory(self.stack_pointer, bytes, value)
+    }
+
+    fn pop(&mut self) -> Result<u32, ErrorKind> {
+        let bytes = self.width.bytes();
+        if self.stack_pointer + bytes > self.memory.len() {
+            return Err(ErrorKind::StackUnderflow);
+        }
+        let value = self.read_memory(self.stack_pointer, bytes)?;
+        self.stack_pointer += bytes;
+        Ok(value)
+    }
+
+    /// Sets Z and N from `res` and clears C and V, as for the logic operations.
+    pub(crate) fn set_logic_flags(&mut self, res: u32) {
+        self.flags = Flags {
+            zero: res == 0,
+            carry: false,
+            negative: res & self.width.sign_bit() != 0,
+            overflow: false,
+        };
+    }
+
+    pub(crate) fn add_with_carry(&mut self, a: u32, b: u32, carry: bool) -> u32 {
+        let wide = a as u64 + b as u64 + carry as u64;
+        let res = wide as u32 & self.width.mask();
+        let sign = self.width.sign_bit();
+        self.flags = Flags {
+            zero: res == 0,
+            carry: wide > self.width.mask() as u64,
+            negative: res & sign != 0,
+            overflow: (a ^ res) & (b ^ res) & sign != 0,
+        };
+        res
+    }
+
+    pub(crate) fn sub_with_borrow(&mut self, a: u32, b: u32, borrow: bool) -> u32 {
+        let wide = a as i64 - b as i64 - borrow as i64;
+        let res = self.width.wrap(wide);
+        let sign = self.width.sign_bit();
+        self.flags = Flags {
+            zero: res == 0,
+            carry: wide < 0,
+            negative: res & sign != 0,
+            overflow: (a ^ b) & (a ^ res) & sign != 0,
+        };
+        res
+    }
+
+    /// Multiplies two words and returns the low and high half of the product.
+    ///
+    /// The flags are the same whichever half is kept: Z and N come from the
+    /// low half, and C and V are set when the product does not fit in it.
+    fn multiply(&mut self, a: u32, b: u32, signed: bool) -> (u32, u32) {
+        let bits = self.width.bits();
+        let product = if signed {
+            self.width.to_signed(a) as i128 * self.width.to_signed(b) as i128
+        } else {
+            a as i128 * b as i128
+        };
+        let low = product as u32 & self.width.mask();
+        let high = (product >> bits) as u32 & self.width.mask();
+        let overflow = if signed {
+            self.width.to_signed(low) as i128 != product
+        } else {
+            high != 0
+        };
+        self.set_logic_flags(low);
+        self.flags.carry = overflow;
+        self.flags.overflow = overflow;
+        (low, high)
+    }
+
+    /// Divides two words and returns the quotient and the remainder, both
+    /// rounded towards zero like in Rust.
+    fn divide(&mut self, a: u32, b: u32, signed: bool) -> Result<(u32, u32), ErrorKind> {
+        if b == 0 {
+            return Err(ErrorKind::DivideByZero(self.index));
+        }
+        let (a, b) = if signed {
+            (self.width.to_signed(a), self.width.to_signed(b))
+        } else {
+            (a as i64, b as i64)
+        };
+        let quotient = a / b;
+        let remainder = a % b;
+        let res = self.width.wrap(quotient);
+        self.set_logic_flags(res);
+        // Only MIN / -1 can overflow, the quotient wraps back to MIN.
+        self.flags.overflow = signed && self.width.to_signed(res) != quotient;
+        Ok((res, self.width.wrap(remainder)))
+    }
+
+    /// Shifts or rotates `val` by `amount` bits and sets the flags.
+    ///
+    /// Shifting by the word width or more moves every bit out: `SHL` and `SHR`
+    /// give 0 and `SAR` fills the word with the sign bit. Rotations are taken
+    /// modulo the word width. The carry receives the last bit shifted out (0
+    /// when all bits were shifted out before the last step), and a shift by 0
+    /// leaves the carry alone.
+    fn shift(&mut self, kind: ShiftKind, val: u32, amount: u32) -> u32 {
+        let bits = self.width.bits();
+        let mask = self.width.mask();
+        let val = val as u64;
+        let bit = |n: u32| n < bits && (val >> n) & 1 == 1;
+        let sign = bit(bits - 1);
+        let (res, carry) = match kind {
+            ShiftKind::Shl if amount >= bits => (0, amount == bits && bit(0)),
+            ShiftKind::Shl => ((val << amount) as u32 & mask, bit(bits - amount)),
+            ShiftKind::Shr if amount >= bits => (0, amount == bits && sign),
+            ShiftKind::Shr => ((val >> amount) as u32, amount > 0 && bit(amount - 1)),
+            ShiftKind::Sar if amount >= bits => (if sign { mask } else { 0 }, sign),
+            ShiftKind::Sar => {
+                let res = self.width.wrap(self.width.to_signed(val as u32) >> amount);
+                (res, amount > 0 && bit(amount - 1))
+            }
+            ShiftKind::Rol => {
+                let n = amount % bits;
+                let res = ((val << n) | (val >> (bits - n))) as u32 & mask;
+                (res, res & 1 == 1)
+            }
+            ShiftKind::Ror => {
+                let n = amount % bits;
+                let res = ((val >> n) | (val << (bits - n))) as u32 & mask;
+                (res, res & self.width.sign_bit() != 0)
+            }
+        };
+        let carry = if amount == 0 { self.flags.carry } else { carry };
+        self.set_logic_flags(res);
+        self.flags.carry = carry;
+        res
+    }
+
+    fn shift_register(&mut self, register: &Register, kind: ShiftKind, amount: u32) {
+        let val = self.get_register(register);
+        let res = self.shift(kind, val, amount);
+        self.modify_register(register, res);
     }
 
-    fn intepret_instruction(&mut self, ins: &Instruction) -> Result<(), ProgramError> {
+    fn jump(&mut self, target: usize) -> Result<(), ErrorKind> {
+        self.index = target;
+        Ok(())
+    }
+
+    pub(crate) fn intepret_instruction(
+        &mut self,
+        ins: &Instruction,
+        program: &ResolvedProgram,
+    ) -> Result<(), ErrorKind> {
+        let target = program.target(self.index);
         match ins {
-            Instruction::Zero(register) => self.modify_register(&register, Wrapping(0)),
+            Instruction::Zero(register) => self.modify_register(register, 0),
             Instruction::Mov(register, register1) => {
-                let val = self.get_register(&register1);
-                self.modify_register(&register, val);
+                let val = self.get_register(register1);
+                self.modify_register(register, val);
             }
             Instruction::Add(register, register1, register2) => {
-                let val1 = self.get_register(&register1);
-                let val2 = self.get_register(&register2);
-                let res = val1 + val2;
-                self.modify_register(&register, res);
-                self.set_flag(res.0 == 0);
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let res = self.add_with_carry(val1, val2, false);
+                self.modify_register(register, res);
+            }
+            Instruction::Adc(register, register1, register2) => {
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let res = self.add_with_carry(val1, val2, self.flags.carry);
+                self.modify_register(register, res);
             }
             Instruction::Sub(register, register1, register2) => {
-                let val1 = self.get_register(&register1);
-                let val2 = self.get_register(&register2);
-                let res = val1 - val2;
-                self.modify_register(&register, res);
-                self.set_flag(res.0 == 0);
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let res = self.sub_with_borrow(val1, val2, false);
+                self.modify_register(register, res);
+            }
+            Instruction::Sbc(register, register1, register2) => {
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let res = self.sub_with_borrow(val1, val2, self.flags.carry);
+                self.modify_register(register, res);
             }
             Instruction::Inc(register) => {
-                let val = self.get_register(&register);
-                let res = val + Wrapping(1);
-                self.modify_register(&register, res);
-                self.set_flag(res.0 == 0);
+                // INC and DEC leave the carry alone so they can drive multi-byte loops.
+                let val = self.get_register(register);
+                let carry = self.flags.carry;
+                let res = self.add_with_carry(val, 1, false);
+                self.flags.carry = carry;
+                self.modify_register(register, res);
             }
             Instruction::Dec(register) => {
-                let val = self.get_register(&register);
-                let res = val - Wrapping(1);
-                self.modify_register(&register, res);
-                self.set_flag(res.0 == 0);
+                let val = self.get_register(register);
+                let carry = self.flags.carry;
+                let res = self.sub_with_borrow(val, 1, false);
+                self.flags.carry = carry;
+                self.modify_register(register, res);
             }
             Instruction::And(register, register1, register2) => {
-                let val1 = self.get_register(&register1);
-                let val2 = self.get_register(&register2);
-                let res = val1.bitand(val2);
-                self.modify_register(&register, res);
-                self.set_flag(res.0 == 0);
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let res = val1 & val2;
+                self.modify_register(register, res);
+                self.set_logic_flags(res);
             }
             Instruction::Or(register, register1, register2) => {
-                let val1 = self.get_register(&register1);
-                let val2 = self.get_register(&register2);
-                let res = val1.bitor(val2);
-                self.modify_register(&register, res);
-                self.set_flag(res.0 == 0);
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let res = val1 | val2;
+                self.modify_register(register, res);
+                self.set_logic_flags(res);
             }
             Instruction::Xor(register, register1, register2) => {
-                let val1 = self.get_register(&register1);
-                let val2 = self.get_register(&register2);
-                let res = val1.bitxor(val2);
-                self.modify_register(&register, res);
-                self.set_flag(res.0 == 0);
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let res = val1 ^ val2;
+                self.modify_register(register, res);
+                self.set_logic_flags(res);
             }
             Instruction::Not(register) => {
-                let val = self.get_register(&register);
-                let res = !val;
-                self.modify_register(&register, res);
-                self.set_flag(res.0 == 0);
+                let val = self.get_register(register);
+                let res = !val & self.width.mask();
+                self.modify_register(register, res);
+                self.set_logic_flags(res);
+            }
+            Instruction::Mul(register, register1, register2) => {
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let (low, _) = self.multiply(val1, val2, false);
+                self.modify_register(register, low);
+            }
+            Instruction::Mulh(register, register1, register2) => {
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let (_, high) = self.multiply(val1, val2, false);
+                self.modify_register(register, high);
+            }
+            Instruction::Imul(register, register1, register2) => {
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let (low, _) = self.multiply(val1, val2, true);
+                self.modify_register(register, low);
+            }
+            Instruction::Imulh(register, register1, register2) => {
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let (_, high) = self.multiply(val1, val2, true);
+                self.modify_register(register, high);
+            }
+            Instruction::Div(register, register1, register2) => {
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let (quotient, _) = self.divide(val1, val2, false)?;
+                self.modify_register(register, quotient);
+            }
+            Instruction::Mod(register, register1, register2) => {
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let (_, remainder) = self.divide(val1, val2, false)?;
+                self.modify_register(register, remainder);
+                self.set_logic_flags(remainder);
+            }
+            Instruction::Idiv(register, register1, register2) => {
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let (quotient, _) = self.divide(val1, val2, true)?;
+                self.modify_register(register, quotient);
+            }
+            Instruction::Imod(register, register1, register2) => {
+                let val1 = self.get_register(register1);
+                let val2 = self.get_register(register2);
+                let (_, remainder) = self.divide(val1, val2, true)?;
+                self.modify_register(register, remainder);
+                self.set_logic_flags(remainder);
             }
             Instruction::Shl(register, k) => {
-                let val = self.get_register(&register);
-                let res = Wrapping(val.0 << k);
-                self.modify_register(&register, res);
-                self.set_flag(res.0 == 0);
+                self.shift_register(register, ShiftKind::Shl, *k as u32)
+            }
+            Instruction::ShlR(register, amount) => {
+                let k = self.get_register(amount);
+                self.shift_register(register, ShiftKind::Shl, k);
             }
             Instruction::Shr(register, k) => {
-                let val = self.get_register(&register);
-                let res = Wrapping(val.0 >> k);
-                self.modify_register(&register, res);
-                self.set_flag(res.0 == 0);
-            }
-            Instruction::Jz(label) => {
-                if self.flag {
-                    self.goto_label(&label)?;
+                self.shift_register(register, ShiftKind::Shr, *k as u32)
+            }
+            Instruction::ShrR(register, amount) => {
+                let k = self.get_register(amount);
+                self.shift_register(register, ShiftKind::Shr, k);
+            }
+            Instruction::Sar(register, k) => {
+                self.shift_register(register, ShiftKind::Sar, *k as u32)
+            }
+            Instruction::SarR(register, amount) => {
+                let k = self.get_register(amount);
+                self.shift_register(register, ShiftKind::Sar, k);
+            }
+            Instruction::Rol(register, k) => {
+                self.shift_register(register, ShiftKind::Rol, *k as u32)
+            }
+            Instruction::RolR(register, amount) => {
+                let k = self.get_register(amount);
+                self.shift_register(register, ShiftKind::Rol, k);
+            }
+            Instruction::Ror(register, k) => {
+                self.shift_register(register, ShiftKind::Ror, *k as u32)
+            }
+            Instruction::RorR(register, amount) => {
+                let k = self.get_register(amount);
+                self.shift_register(register, ShiftKind::Ror, k);
+            }
+            Instruction::J(_) => return self.jump(target),
+            Instruction::Jz(_) if self.flags.zero => return self.jump(target),
+            Instruction::Jnz(_) if !self.flags.zero => return self.jump(target),
+            Instruction::Jc(_) if self.flags.carry => return self.jump(target),
+            Instruction::Jnc(_) if !self.flags.carry => return self.jump(target),
+            Instruction::Jn(_) if self.flags.negative => return self.jump(target),
+            Instruction::Jv(_) if self.flags.overflow => return self.jump(target),
+            Instruction::Jlt(_) if self.flags.negative != self.flags.overflow => {
+                return self.jump(target)
+            }
+            Instruction::Jge(_) if self.flags.negative == self.flags.overflow => {
+                return self.jump(target)
+            }
+            Instruction::Jb(_) if self.flags.carry => return self.jump(target),
+            Instruction::Jae(_) if !self.flags.carry => return self.jump(target),
+            // Branches that are not taken.
+            Instruction::Jz(_)
+            | Instruction::Jnz(_)
+            | Instruction::Jc(_)
+            | Instruction::Jnc(_)
+            | Instruction::Jn(_)
+            | Instruction::Jv(_)
+            | Instruction::Jlt(_)
+            | Instruction::Jge(_)
+            | Instruction::Jb(_)
+            | Instruction::Jae(_) => {}
+            Instruction::Set(register, k) => {
+                self.modify_register(register, *k);
+            }
+            Instruction::AddI(register, register1, k) => {
+                let val = self.get_register(register1);
+                let res = self.add_with_carry(val, *k, false);
+                self.modify_register(register, res);
+            }
+            Instruction::SubI(register, register1, k) => {
+                let val = self.get_register(register1);
+                let res = self.sub_with_borrow(val, *k, false);
+                self.modify_register(register, res);
+            }
+            Instruction::AndI(register, register1, k) => {
+                let res = self.get_register(register1) & k;
+                self.modify_register(register, res);
+                self.set_logic_flags(res);
+            }
+            Instruction::OrI(register, register1, k) => {
+                let res = self.get_register(register1) | k;
+                self.modify_register(register, res);
+                self.set_logic_flags(res);
+            }
+            Instruction::XorI(register, register1, k) => {
+                let res = self.get_register(register1) ^ k;
+                self.modify_register(register, res);
+                self.set_logic_flags(res);
+            }
+            Instruction::Cmp(register, register1) => {
+                let val = self.get_register(register);
+                let val1 = self.get_register(register1);
+                self.sub_with_borrow(val, val1, false);
+            }
+            Instruction::CmpI(register, k) => {
+                let val = self.get_register(register);
+                self.sub_with_borrow(val, *k, false);
+            }
+            Instruction::Load(register, address) => {
+                let address = self.get_register(address) as usize;
+                let val = self.read_memory(address, self.width.bytes())?;
+                self.modify_register(register, val);
+            }
+            Instruction::LoadI(register, address) => {
+                let val = self.read_memory(*address as usize, self.width.bytes())?;
+                self.modify_register(register, val);
+            }
+            Instruction::Store(register, address) => {
+                let address = self.get_register(address) as usize;
+                let val = self.get_register(register);
+                self.write_memory(address, self.width.bytes(), val)?;
+            }
+            Instruction::StoreI(register, address) => {
+                let val = self.get_register(register);
+                self.write_memory(*address as usize, self.width.bytes(), val)?;
+            }
+            Instruction::LoadB(register, address) => {
+                let address = self.get_register(address) as usize;
+                let val = self.read_memory(address, 1)?;
+                self.modify_register(register, val);
+            }
+            Instruction::LoadBI(register, address) => {
+                let val = self.read_memory(*address as usize, 1)?;
+                self.modify_register(register, val);
+            }
+            Instruction::StoreB(register, address) => {
+                let address = self.get_register(address) as usize;
+                let val = self.get_register(register);
+                self.write_memory(address, 1, val)?;
+            }
+            Instruction::StoreBI(register, address) => {
+                let val = self.get_register(register);
+                self.write_memory(*address as usize, 1, val)?;
+            }
+            Instruction::Push(register) => {
+                let val = self.get_register(register);
+                self.push(val)?;
+            }
+            Instruction::Pop(register) => {
+                let val = self.pop()?;
+                self.modify_register(register, val);
+            }
+            Instruction::In(register, port) => {
+                let device = self
+                    .devices
+                    .get_mut(port)
+                    .ok_or(ErrorKind::NoDevice(*port))?;
+                let val = device.read(*port).ok_or(ErrorKind::NoInput(*port))?;
+                self.modify_register(register, val);
+            }
+            Instruction::Out(port, register) => {
+                let val = self.get_register(register);
+                let device = self
+                    .devices
+                    .get_mut(port)
+                    .ok_or(ErrorKind::NoDevice(*port))?;
+                device.write(*port, val);
+            }
+            // The index stays on the HALT, so stepping again halts again.
+            Instruction::Halt => return Err(ErrorKind::Halted(0)),
+            Instruction::HaltR(register) => {
+                return Err(ErrorKind::Halted(self.get_register(register)))
+            }
+            Instruction::Jr(register) => {
+                let address = self.get_register(register);
+                if address as usize > program.len() {
+                    return Err(ErrorKind::InvalidJumpTarget(address));
                 }
+                return self.jump(address as usize);
             }
-            Instruction::Jnz(label) => {
-                if !self.flag {
-                    self.goto_label(&label)?;
+            Instruction::La(register, _) => {
+                if target > self.width.mask() as usize {
+                    return Err(ErrorKind::AddressOutOfRange(target));
                 }
+                self.modify_register(register, target as u32);
             }
-            Instruction::J(label) => self.goto_label(&label)?,
-            Instruction::Set(register, k) => {
-                self.modify_register(&register, Wrapping(*k));
+            Instruction::Call(_) => {
+                if self.call_stack.len() >= self.call_depth {
+                    return Err(ErrorKind::CallStackOverflow(self.call_depth));
+                }
+                self.call_stack.push(self.index);
+                return self.jump(target);
+            }
+            Instruction::Ret => {
+                let Some(index) = self.call_stack.pop() else {
+                    return Err(ErrorKind::EmptyCallStack);
+                };
+                self.index = index;
             }
         };
         self.index += 1;
         Ok(())
     }
-
-    pub fn step(&mut self) -> Result<(), ProgramError> {
-        let Some(line) = self.program.get(self.index) else {
-            return Err(ProgramError::EndOfProgram);
-        };
-
-        match line {
-            ProgramLine::Ins(instruction) => self.intepret_instruction(&instruction.clone())?,
-            ProgramLine::Lbl(_) => {
-                self.index += 1;
-            }
-        }
-
-        Ok(())
-    }
 }
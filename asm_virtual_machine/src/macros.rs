@@ -0,0 +1,652 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::diagnostic::{Diagnostic, Sources};
+use crate::machine::{Expansion, Span};
+use crate::parser::MNEMONICS;
+
+/// A `.macro NAME params` … `.endm` block.
+#[derive(Clone)]
+struct Macro {
+    name: String,
+    /// The file the macro is defined in.
+    file: Option<Arc<str>>,
+    params: Vec<String>,
+    /// Number and text of every line between `.macro` and `.endm`.
+    body: Vec<(usize, Vec<char>)>,
+    /// Labels defined in the body, which get a new name in every expansion
+    /// so that the macro can be used more than once.
+    labels: HashSet<String>,
+}
+
+/// A name that was replaced in an expanded line. Columns count from 1.
+#[derive(Debug, Clone)]
+struct Substitution {
+    /// Columns of the replacement in the expanded line.
+    expanded: Range<usize>,
+    /// Columns of the name in the source line.
+    original: Range<usize>,
+}
+
+/// Where a line of the expanded text was written.
+#[derive(Debug, Clone)]
+struct Origin {
+    file: Option<Arc<str>>,
+    line: usize,
+    substitutions: Vec<Substitution>,
+    expansion: Option<Arc<Expansion>>,
+}
+
+impl Origin {
+    /// A line that was not expanded from a macro.
+    fn new(file: Option<Arc<str>>, line: usize) -> Origin {
+        Origin {
+            file,
+            line,
+            substitutions: Vec::new(),
+            expansion: None,
+        }
+    }
+
+    /// Maps a column of the expanded line to the source. A column inside a
+    /// replaced name maps to the start of that name.
+    fn column(&self, column: usize) -> usize {
+        let mut shift = 0;
+        for substitution in &self.substitutions {
+            if column < substitution.expanded.start {
+                break;
+            }
+            if column < substitution.expanded.end {
+                return substitution.original.start;
+            }
+            shift = substitution.original.end as isize - substitution.expanded.end as isize;
+        }
+        column.saturating_add_signed(shift)
+    }
+
+    fn end_column(&self, end_column: usize) -> usize {
+        let Some(last) = end_column.checked_sub(1).filter(|&last| last > 0) else {
+            return end_column;
+        };
+        match self
+            .substitutions
+            .iter()
+            .find(|substitution| substitution.expanded.contains(&last))
+        {
+            Some(substitution) => substitution.original.end,
+            None => self.column(last) + 1,
+        }
+    }
+
+    /// The source span of the columns `columns` of the expanded line.
+    fn span(&self, columns: Range<usize>) -> Span {
+        let column = self.column(columns.start);
+        Span {
+            file: self.file.clone(),
+            line: self.line,
+            column,
+            end_column: self.end_column(columns.end).max(column),
+            expansion: self.expansion.clone(),
+        }
+    }
+}
+
+/// The source with every macro call replaced by the body of the macro.
+pub(crate) struct Expanded {
+    pub text: String,
+    origins: Vec<Origin>,
+    /// Problems with the macros themselves, like calling one with the wrong
+    /// number of arguments.
+    pub diagnostics: Vec<Diagnostic>,
+    /// The text of the file and of every file it includes, by name.
+    pub sources: Sources,
+}
+
+impl Expanded {
+    /// Maps a span in `text` back to where it was written.
+    pub fn locate(&self, span: &Span) -> Span {
+        let origin = self
+            .origins
+            .get(span.line.saturating_sub(1))
+            .or(self.origins.last());
+        match origin {
+            Some(origin) => origin.span(span.column..span.end_column),
+            None => span.clone(),
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn is_label_char(c: char) -> bool {
+    is_word_char(c) || c == '-'
+}
+
+/// A name for a macro or parameter, like the names of `.equ` constants.
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(is_word_char)
+}
+
+/// Index of the first character after a character or string literal
+/// starting at `start`, or `start + 1` if it is a lone quote.
+fn skip_literal(text: &[char], start: usize) -> usize {
+    let quote = text[start];
+    let mut i = start + 1;
+    while i < text.len() && text[i] != quote {
+        i += if text[i] == '\\' { 2 } else { 1 };
+    }
+    if i < text.len() && (quote == '"' || i <= start + 3) {
+        i + 1
+    } else {
+        start + 1
+    }
+}
+
+/// Index where the comment of a line starts, or its length.
+fn code_end(text: &[char]) -> usize {
+    let mut i = 0;
+    while i < text.len() {
+        match text[i] {
+            '\'' | '"' => {
+                i = skip_literal(text, i);
+                continue;
+            }
+            ';' | '#' => return i,
+            '/' if text.get(i + 1) == Some(&'/') => return i,
+            _ => {}
+        }
+        i += 1;
+    }
+    text.len()
+}
+
+/// The parts of a line that decide whether it defines or calls a macro.
+struct Statement {
+    labels: Vec<String>,
+    /// The mnemonic, directive or macro name after the labels.
+    word: Range<usize>,
+    code_end: usize,
+}
+
+impl Statement {
+    fn of(text: &[char]) -> Statement {
+        let code_end = code_end(text);
+        let skip_space = |mut i: usize| {
+            while i < code_end && text[i].is_whitespace() {
+                i += 1;
+            }
+            i
+        };
+        let mut labels = Vec::new();
+        let mut start = skip_space(0);
+        loop {
+            let mut end = start;
+            while end < code_end && is_label_char(text[end]) {
+                end += 1;
+            }
+            if end < code_end && text[end] == ':' && end > start {
+                labels.push(text[start..end].iter().collect());
+                start = skip_space(end + 1);
+            } else {
+                return Statement {
+                    labels,
+                    word: start..end,
+                    code_end,
+                };
+            }
+        }
+    }
+
+    fn word(&self, text: &[char]) -> String {
+        text[self.word.clone()].iter().collect()
+    }
+
+    /// The operands after the word, split at the commas that are not inside
+    /// brackets or parentheses, with the columns where each of them is.
+    fn operands(&self, text: &[char]) -> Vec<(Range<usize>, String)> {
+        let mut operands = Vec::new();
+        let mut depth = 0;
+        let mut start = self.word.end;
+        let mut i = start;
+        loop {
+            if i < self.code_end {
+                match text[i] {
+                    '(' | '[' => depth += 1,
+                    ')' | ']' => depth -= 1,
+                    '\'' | '"' => {
+                        i = skip_literal(text, i);
+                        continue;
+                    }
+                    _ => {}
+                }
+                if text[i] != ',' || depth != 0 {
+                    i += 1;
+                    continue;
+                }
+            }
+            let mut first = start;
+            let mut end = i.min(self.code_end);
+            while first < end && text[first].is_whitespace() {
+                first += 1;
+            }
+            while end > first && text[end - 1].is_whitespace() {
+                end -= 1;
+            }
+            operands.push((first..end, text[first..end].iter().collect::<String>()));
+            if i >= self.code_end {
+                break;
+            }
+            i += 1;
+            start = i;
+        }
+        if let [(_, only)] = operands.as_slice() {
+            if only.is_empty() {
+                operands.clear();
+            }
+        }
+        operands
+    }
+}
+
+/// Copies `text`, replacing every name that `replace` knows. Comments and
+/// literals are copied as they are.
+fn substitute(
+    text: &[char],
+    replace: impl Fn(&str) -> Option<String>,
+) -> (Vec<char>, Vec<Substitution>) {
+    let code_end = code_end(text);
+    let mut expanded = Vec::with_capacity(text.len());
+    let mut substitutions = Vec::new();
+    let mut i = 0;
+    while i < code_end {
+        if text[i] == '\'' || text[i] == '"' {
+            let end = skip_literal(text, i).min(code_end);
+            expanded.extend_from_slice(&text[i..end]);
+            i = end;
+            continue;
+        }
+        if !is_word_char(text[i]) {
+            expanded.push(text[i]);
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end < code_end && is_word_char(text[end]) {
+            end += 1;
+        }
+        // A label can also contain '-', which otherwise reads as a
+        // subtraction, so the longer name is tried first.
+        let mut label_end = end;
+        while label_end < code_end && is_label_char(text[label_end]) {
+            label_end += 1;
+        }
+        let label: String = text[i..label_end].iter().collect();
+        let replacement = match replace(&label) {
+            Some(replacement) if label_end > end => {
+                end = label_end;
+                Some(replacement)
+            }
+            _ => replace(&text[i..end].iter().collect::<String>()),
+        };
+        match replacement {
+            Some(replacement) => {
+                let start = expanded.len() + 1;
+                expanded.extend(replacement.chars());
+                substitutions.push(Substitution {
+                    expanded: start..expanded.len() + 1,
+                    original: i + 1..end + 1,
+                });
+            }
+            None => expanded.extend_from_slice(&text[i..end]),
+        }
+        i = end;
+    }
+    expanded.extend_from_slice(&text[code_end..]);
+    (expanded, substitutions)
+}
+
+struct Expander {
+    /// Every macro by its name in upper case, as calls ignore case like
+    /// mnemonics do.
+    macros: HashMap<String, Macro>,
+    /// Number of expansions so far, used to make their labels unique.
+    count: usize,
+    lines: Vec<String>,
+    origins: Vec<Origin>,
+    diagnostics: Vec<Diagnostic>,
+    /// The text of every file that was read, by name.
+    sources: Sources,
+}
+
+impl Expander {
+    fn error(&mut self, origin: &Origin, columns: Range<usize>, message: String) {
+        self.diagnostics.push(Diagnostic {
+            span: origin.span(columns.start + 1..columns.end + 1),
+            message,
+        });
+    }
+
+    /// Reads a `.macro` line. Returns a macro without a name if the line is
+    /// broken, so that its body is still skipped.
+    fn define(&mut self, origin: &Origin, text: &[char], statement: &Statement) -> Macro {
+        let mut definition = Macro {
+            name: String::new(),
+            file: origin.file.clone(),
+            params: Vec::new(),
+            body: Vec::new(),
+            labels: HashSet::new(),
+        };
+        if !statement.labels.is_empty() {
+            self.error(
+                origin,
+                0..statement.word.start,
+                "a label cannot go on a '.macro' line".to_string(),
+            );
+        }
+        let mut names = Vec::new();
+        let mut i = statement.word.end;
+        while i < statement.code_end {
+            if text[i].is_whitespace() || text[i] == ',' {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < statement.code_end && !text[i].is_whitespace() && text[i] != ',' {
+                i += 1;
+            }
+            names.push((start..i, text[start..i].iter().collect::<String>()));
+        }
+        let Some((columns, name)) = names.first().cloned() else {
+            self.error(
+                origin,
+                statement.word.clone(),
+                "missing macro name, write it like '.macro SWAP a, b'".to_string(),
+            );
+            return definition;
+        };
+        let mut valid = true;
+        if !is_name(&name) {
+            self.error(
+                origin,
+                columns.clone(),
+                format!("'{}' is not a valid macro name", name),
+            );
+            valid = false;
+        } else if MNEMONICS
+            .iter()
+            .any(|(mnemonic, _)| mnemonic.eq_ignore_ascii_case(&name))
+        {
+            self.error(
+                origin,
+                columns.clone(),
+                format!("'{}' is an instruction and cannot be a macro name", name),
+            );
+            valid = false;
+        } else if self.macros.contains_key(&name.to_uppercase()) {
+            self.error(
+                origin,
+                columns.clone(),
+                format!("macro {} is already defined", name),
+            );
+            valid = false;
+        }
+        for (columns, param) in &names[1..] {
+            if !is_name(param) {
+                self.error(
+                    origin,
+                    columns.clone(),
+                    format!("'{}' is not a valid parameter name", param),
+                );
+            } else if definition.params.contains(param) {
+                self.error(
+                    origin,
+                    columns.clone(),
+                    format!("parameter '{}' is already defined", param),
+                );
+            } else {
+                definition.params.push(param.clone());
+            }
+        }
+        if valid {
+            definition.name = name;
+        }
+        definition
+    }
+
+    /// Adds one line of code, expanding it if it calls a macro. `stack` is
+    /// the names of the macros being expanded.
+    fn line(&mut self, text: Vec<char>, origin: Origin, stack: &mut Vec<String>) {
+        let statement = Statement::of(&text);
+        let key = statement.word(&text).to_uppercase();
+        let Some(definition) = self.macros.get(&key).cloned() else {
+            self.lines.push(text.into_iter().collect());
+            self.origins.push(origin);
+            return;
+        };
+        if !statement.labels.is_empty() {
+            self.lines
+                .push(text[..statement.word.start].iter().collect());
+            self.origins.push(origin.clone());
+        }
+        let mut end = statement.code_end;
+        while end > statement.word.start && text[end - 1].is_whitespace() {
+            end -= 1;
+        }
+        let call = statement.word.start..end;
+        if stack.contains(&key) {
+            let message = format!("macro {} calls itself", definition.name);
+            self.error(&origin, call, message);
+            return;
+        }
+        let arguments = statement.operands(&text);
+        if arguments.len() != definition.params.len() {
+            let expected = match definition.params.len() {
+                0 => "no arguments".to_string(),
+                1 => format!("1 argument ({})", definition.params[0]),
+                n => format!("{} arguments ({})", n, definition.params.join(", ")),
+            };
+            let message = format!(
+                "macro {} takes {}, found {}",
+                definition.name,
+                expected,
+                arguments.len()
+            );
+            self.error(&origin, call, message);
+            return;
+        }
+        if let Some((columns, _)) = arguments.iter().find(|(_, text)| text.is_empty()) {
+            let columns = columns.start.saturating_sub(1)..columns.start;
+            self.error(&origin, columns, "missing argument".to_string());
+            return;
+        }
+
+        self.count += 1;
+        let count = self.count;
+        let expansion = Arc::new(Expansion {
+            name: definition.name.clone(),
+            call: origin.span(call.start + 1..call.end + 1),
+        });
+        stack.push(key);
+        for (number, body) in &definition.body {
+            let (text, substitutions) = substitute(body, |name| {
+                if let Some(n) = definition.params.iter().position(|param| param == name) {
+                    Some(arguments[n].1.clone())
+                } else if definition.labels.contains(name) {
+                    Some(format!("{}.{}.{}", definition.name, count, name))
+                } else {
+                    None
+                }
+            });
+            let origin = Origin {
+                file: definition.file.clone(),
+                line: *number,
+                substitutions,
+                expansion: Some(expansion.clone()),
+            };
+            self.line(text, origin, stack);
+        }
+        stack.pop();
+    }
+
+    /// Reads the lines of `source`, keeping its macros and adding the lines
+    /// of code outside them to `code`. Included files are read in place.
+    /// `including` is the files that are being read, to catch a file that
+    /// includes itself.
+    fn read(
+        &mut self,
+        source: &str,
+        file: Option<Arc<str>>,
+        code: &mut Vec<(Origin, Vec<char>)>,
+        including: &mut Vec<PathBuf>,
+    ) {
+        // The `.macro` line of the macro being defined, and where its word is.
+        let mut current: Option<(Origin, Range<usize>, Macro)> = None;
+        for (n, line) in source.split('\n').enumerate() {
+            let origin = Origin::new(file.clone(), n + 1);
+            let text: Vec<char> = line.chars().collect();
+            let statement = Statement::of(&text);
+            match statement.word(&text).to_lowercase().as_str() {
+                ".macro" => {
+                    if current.is_some() {
+                        self.error(
+                            &origin,
+                            statement.word.clone(),
+                            "a macro cannot be defined inside another macro".to_string(),
+                        );
+                        continue;
+                    }
+                    let definition = self.define(&origin, &text, &statement);
+                    current = Some((origin, statement.word, definition));
+                }
+                ".endm" => match current.take() {
+                    Some((_, _, definition)) => {
+                        if !definition.name.is_empty() {
+                            let key = definition.name.to_uppercase();
+                            self.macros.insert(key, definition);
+                        }
+                    }
+                    None => self.error(
+                        &origin,
+                        statement.word.clone(),
+                        "'.endm' without a '.macro'".to_string(),
+                    ),
+                },
+                ".include" if current.is_some() => self.error(
+                    &origin,
+                    statement.word.clone(),
+                    "files cannot be included inside a macro".to_string(),
+                ),
+                ".include" => self.include(&origin, &text, &statement, code, including),
+                _ => match &mut current {
+                    Some((_, _, definition)) => {
+                        definition.labels.extend(statement.labels);
+                        definition.body.push((origin.line, text));
+                    }
+                    None => code.push((origin, text)),
+                },
+            }
+        }
+        if let Some((origin, word, _)) = current {
+            self.error(
+                &origin,
+                word,
+                "this '.macro' is missing its '.endm'".to_string(),
+            );
+        }
+    }
+
+    /// Reads the file named by an `.include "path"` line. The path is
+    /// relative to the file with the `.include`.
+    fn include(
+        &mut self,
+        origin: &Origin,
+        text: &[char],
+        statement: &Statement,
+        code: &mut Vec<(Origin, Vec<char>)>,
+        including: &mut Vec<PathBuf>,
+    ) {
+        let operand: String = text[statement.word.end..statement.code_end]
+            .iter()
+            .collect();
+        let name = operand
+            .trim()
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+            .filter(|name| !name.is_empty() && !name.contains('"'));
+        let Some(name) = name else {
+            let message = "write it like '.include \"lib.asm\"'".to_string();
+            self.error(origin, statement.word.start..statement.code_end, message);
+            return;
+        };
+        let path = match &origin.file {
+            Some(file) => Path::new(&**file).with_file_name(name),
+            None => PathBuf::from(name),
+        };
+        let identity = identity(&path);
+        if including.contains(&identity) {
+            let message = format!("'{}' includes itself", path.display());
+            self.error(origin, statement.word.start..statement.code_end, message);
+            return;
+        }
+        match fs::read_to_string(&path) {
+            Ok(source) => {
+                let file: Arc<str> = Arc::from(path.to_string_lossy());
+                self.sources
+                    .insert(file.clone(), Arc::from(source.as_str()));
+                including.push(identity);
+                self.read(&source, Some(file), code, including);
+                including.pop();
+            }
+            Err(error) => {
+                let message = format!("cannot read '{}': {}", path.display(), error);
+                self.error(origin, statement.word.start..statement.code_end, message);
+            }
+        }
+    }
+}
+
+/// Collects the macros defined in `source` and the files it includes, and
+/// expands every call of a macro. Definitions can come after the calls, like
+/// labels.
+pub(crate) fn expand(source: &str, file: Option<Arc<str>>) -> Expanded {
+    let mut expander = Expander {
+        macros: HashMap::new(),
+        count: 0,
+        lines: Vec::new(),
+        origins: Vec::new(),
+        diagnostics: Vec::new(),
+        sources: HashMap::new(),
+    };
+    let mut including = Vec::new();
+    if let Some(file) = &file {
+        expander.sources.insert(file.clone(), Arc::from(source));
+        including.push(identity(Path::new(&**file)));
+    }
+    let mut code = Vec::new();
+    expander.read(source, file, &mut code, &mut including);
+    for (origin, text) in code {
+        expander.line(text, origin, &mut Vec::new());
+    }
+    Expanded {
+        text: expander.lines.join("\n"),
+        origins: expander.origins,
+        diagnostics: expander.diagnostics,
+        sources: expander.sources,
+    }
+}
+
+/// Tells files apart however they are named.
+fn identity(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
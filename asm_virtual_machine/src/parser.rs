@@ -1,86 +1,1393 @@
-use pest::Parser;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{Arc, LazyLock},
+};
+
+use pest::{
+    error::{ErrorVariant, LineColLocation},
+    iterators::{Pair, Pairs},
+    pratt_parser::{Assoc, Op, PrattParser},
+    Parser,
+};
 use pest_derive::Parser;
 
-use crate::machine::{Instruction, Label, ProgramLine, Register};
+use crate::diagnostic::{Diagnostic, ParseError, Sources};
+use crate::linker::{overlapping_data, Module, Moves, Place, Relocation};
+use crate::machine::{Instruction, Label, MachineConfig, ProgramLine, Register, Span};
+use crate::macros::{self, Expanded};
+use crate::word::WordWidth;
 
 #[derive(Parser)]
 #[grammar = "./asm.pest"]
-struct ASMProgramParser;
+pub(crate) struct ASMProgramParser;
 
-pub fn parse_program(file: &str) -> anyhow::Result<Vec<ProgramLine>> {
-    let prg = ASMProgramParser::parse(Rule::Program, file)?;
-    let mut result: Vec<ProgramLine> = Vec::new();
+/// Every mnemonic the grammar knows, with an example of its operands. Used
+/// to suggest a mnemonic for a typo and to show how an instruction is written.
+pub(crate) const MNEMONICS: &[(&str, &str)] = &[
+    ("ZERO", "R1"),
+    ("MOV", "R1, R2"),
+    ("ADD", "R1, R2, R3"),
+    ("ADC", "R1, R2, R3"),
+    ("SUB", "R1, R2, R3"),
+    ("SBC", "R1, R2, R3"),
+    ("INC", "R1"),
+    ("DEC", "R1"),
+    ("AND", "R1, R2, R3"),
+    ("OR", "R1, R2, R3"),
+    ("XOR", "R1, R2, R3"),
+    ("NOT", "R1"),
+    ("MUL", "R1, R2, R3"),
+    ("MULH", "R1, R2, R3"),
+    ("IMUL", "R1, R2, R3"),
+    ("IMULH", "R1, R2, R3"),
+    ("DIV", "R1, R2, R3"),
+    ("MOD", "R1, R2, R3"),
+    ("IDIV", "R1, R2, R3"),
+    ("IMOD", "R1, R2, R3"),
+    ("SHL", "R1, 3"),
+    ("SHR", "R1, 3"),
+    ("SAR", "R1, 3"),
+    ("ROL", "R1, 3"),
+    ("ROR", "R1, 3"),
+    ("SET", "R1, 42"),
+    ("ADDI", "R1, R2, 42"),
+    ("SUBI", "R1, R2, 42"),
+    ("ANDI", "R1, R2, 42"),
+    ("ORI", "R1, R2, 42"),
+    ("XORI", "R1, R2, 42"),
+    ("CMPI", "R1, 42"),
+    ("LOAD", "R1, [R2]"),
+    ("STORE", "R1, [R2]"),
+    ("LOADB", "R1, [R2]"),
+    ("STOREB", "R1, [R2]"),
+    ("PUSH", "R1"),
+    ("POP", "R1"),
+    ("J", "label"),
+    ("JZ", "label"),
+    ("JNZ", "label"),
+    ("JC", "label"),
+    ("JNC", "label"),
+    ("JN", "label"),
+    ("JV", "label"),
+    ("JLT", "label"),
+    ("JGE", "label"),
+    ("JB", "label"),
+    ("JAE", "label"),
+    ("CALL", "label"),
+    ("RET", ""),
+    ("JR", "R1"),
+    ("LA", "R1, label"),
+    ("HALT", "R1"),
+    ("IN", "R1, 0"),
+    ("OUT", "0, R1"),
+    ("NEG", "R1"),
+    ("CLR", "R1"),
+    ("CMP", "R1, R2"),
+    ("BEQ", "R1, R2, label"),
+    ("BNE", "R1, R2, label"),
+    ("LI", "R1, 42"),
+    ("NOP", ""),
+];
+
+/// Where the text matched by `pair` is, up to the end of its first line.
+fn span_of(pair: &Pair<Rule>) -> Span {
+    let (line, column) = pair.line_col();
+    let text = pair.as_str().lines().next().unwrap_or("").trim_end();
+    Span {
+        line,
+        column,
+        end_column: column + text.chars().count(),
+        ..Span::default()
+    }
+}
+
+/// Points a diagnostic at the text matched by `pair`.
+fn error(pair: &Pair<Rule>, message: String) -> Diagnostic {
+    Diagnostic {
+        span: span_of(pair),
+        message,
+    }
+}
+
+/// Number of single character edits that turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Says that `text` is not a mnemonic, and which one it may be a typo of.
+/// Only a mnemonic that keeps at least half of `text` is suggested, so
+/// `foo` does not get `MOD`.
+fn unknown_mnemonic(text: &str) -> String {
+    let upper = text.to_uppercase();
+    let closest = MNEMONICS
+        .iter()
+        .map(|(mnemonic, _)| (edit_distance(&upper, mnemonic), mnemonic))
+        .min();
+    match closest {
+        Some((distance, mnemonic)) if distance <= 2 && distance * 2 <= text.len() => {
+            format!("unknown mnemonic '{}', did you mean '{}'?", text, mnemonic)
+        }
+        _ => format!("unknown mnemonic '{}'", text),
+    }
+}
+
+/// The parts of a matched rule, in order. The grammar decides how many there
+/// are, so running out of them means the grammar and this file disagree.
+struct Operands<'i> {
+    pair: Pair<'i, Rule>,
+    inner: Pairs<'i, Rule>,
+}
+
+impl<'i> Operands<'i> {
+    fn of(pair: Pair<'i, Rule>) -> Operands<'i> {
+        Operands {
+            inner: pair.clone().into_inner(),
+            pair,
+        }
+    }
+
+    fn next(&mut self) -> Result<Pair<'i, Rule>, Diagnostic> {
+        self.inner
+            .next()
+            .ok_or_else(|| error(&self.pair, format!("incomplete {:?}", self.pair.as_rule())))
+    }
+
+    fn register(&mut self, scope: &Scope) -> Result<Register, Diagnostic> {
+        scope.register(&self.next()?)
+    }
+
+    fn label(&mut self) -> Result<Label, Diagnostic> {
+        Ok(Label(self.next()?.as_str().to_string()))
+    }
+
+    /// A constant that has to fit in a word.
+    fn word(&mut self, scope: &Scope) -> Result<u32, Diagnostic> {
+        scope.word(&self.next()?)
+    }
+
+    fn port(&mut self, scope: &Scope) -> Result<u8, Diagnostic> {
+        scope.byte(&self.next()?, |port| {
+            format!("port {} does not exist, ports go up to 255", port)
+        })
+    }
+}
+
+/// Reads a decimal, hex (`0x`), binary (`0b`) or character (`'A'`) literal.
+fn parse_number(literal: &Pair<Rule>) -> Result<i64, Diagnostic> {
+    let Some(literal) = literal.clone().into_inner().next() else {
+        return Err(error(literal, "expected a number".to_string()));
+    };
+    let text = literal.as_str();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let too_large = |_| error(&literal, format!("number {} is too large", text));
+    let value = match literal.as_rule() {
+        Rule::HexLiteral => i64::from_str_radix(&digits[2..], 16).map_err(too_large)?,
+        Rule::BinLiteral => i64::from_str_radix(&digits[2..], 2).map_err(too_large)?,
+        Rule::CharLiteral => {
+            let inner = &text[1..text.len() - 1];
+            let c = match inner.strip_prefix('\\') {
+                Some(escaped) => escaped
+                    .chars()
+                    .next()
+                    .and_then(escape)
+                    .ok_or_else(|| error(&literal, format!("unknown escape {}", text)))?,
+                None => inner.chars().next().unwrap_or('\0'),
+            };
+            c as i64
+        }
+        _ => digits.parse().map_err(too_large)?,
+    };
+    Ok(if negative { -value } else { value })
+}
+
+/// The character that `\c` stands for in a character or string literal.
+fn escape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '\'' | '"' => Some(c),
+        _ => None,
+    }
+}
+
+/// The bytes of a string literal in UTF-8, without the quotes.
+fn string_bytes(literal: &Pair<Rule>) -> Result<Vec<u8>, Diagnostic> {
+    let text = literal.as_str();
+    let mut chars = text[1..text.len() - 1].chars();
+    let mut bytes = Vec::new();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => chars
+                .next()
+                .and_then(escape)
+                .ok_or_else(|| error(literal, format!("unknown escape in {}", text)))?,
+            c => c,
+        };
+        bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    Ok(bytes)
+}
 
-    for pair in prg {
-        let ins = pair.into_inner().next().unwrap();
-
-        let line = match ins.as_rule() {
-            Rule::label => {
-                let label = ins.into_inner().next().unwrap().as_str();
-                ProgramLine::Lbl(Label(label.to_string()))
-            }
-            Rule::JumpIns => {
-                let mut registers = ins.into_inner();
-                let action = registers.next().unwrap().as_str();
-                let label = registers.next().unwrap().as_str();
-                let action = match action {
-                    "J" => Instruction::J(Label(label.to_string())),
-                    "JZ" => Instruction::Jz(Label(label.to_string())),
-                    "JNZ" => Instruction::Jnz(Label(label.to_string())),
-                    _ => unreachable!(),
+/// How the registers of the machine are written, like `R0–R7`.
+fn register_range(config: &MachineConfig) -> String {
+    format!("R0–R{}", config.register_count.saturating_sub(1))
+}
+
+/// Order of the operators in an `Expression`, loosest first, like in C.
+static OPERATORS: LazyLock<PrattParser<Rule>> = LazyLock::new(|| {
+    PrattParser::new()
+        .op(Op::infix(Rule::BitOr, Assoc::Left))
+        .op(Op::infix(Rule::BitAnd, Assoc::Left))
+        .op(Op::infix(Rule::Shl, Assoc::Left) | Op::infix(Rule::Shr, Assoc::Left))
+        .op(Op::infix(Rule::Add, Assoc::Left) | Op::infix(Rule::Sub, Assoc::Left))
+        .op(Op::infix(Rule::Mul, Assoc::Left) | Op::infix(Rule::Div, Assoc::Left))
+        .op(Op::prefix(Rule::Neg) | Op::prefix(Rule::BitNot))
+});
+
+/// The value of an expression.
+#[derive(Debug, Clone, Copy)]
+struct Value {
+    number: i64,
+    /// How `number` changes when the linker moves the module, or `None` if
+    /// labels are used in a way the linker cannot follow.
+    moves: Option<Moves>,
+}
+
+/// The names that operands can refer to.
+struct Scope<'a, 'i> {
+    config: &'a MachineConfig,
+    aliases: HashMap<String, Register>,
+    /// Address of every label.
+    labels: HashMap<String, usize>,
+    /// The labels that are in the data section, with how they move when the
+    /// module is linked.
+    data_labels: HashMap<String, Moves>,
+    /// The expression of every `.equ`, evaluated where it is used.
+    constants: HashMap<String, Pair<'i, Rule>>,
+}
+
+impl<'i> Scope<'_, 'i> {
+    /// Resolves a register name or alias and checks that the machine has it.
+    fn register(&self, name: &Pair<Rule>) -> Result<Register, Diagnostic> {
+        let text = name.as_str();
+        let config = self.config;
+        let register = match self.aliases.get(text) {
+            Some(register) => *register,
+            None => Register::try_from(text).map_err(|_| {
+                error(
+                    name,
+                    format!(
+                        "expected register {} or an alias, found '{}'",
+                        register_range(config),
+                        text
+                    ),
+                )
+            })?,
+        };
+        if register.0 as usize >= config.register_count {
+            return Err(error(
+                name,
+                format!(
+                    "expected register {}, found {}",
+                    register_range(config),
+                    text
+                ),
+            ));
+        }
+        Ok(register)
+    }
+
+    fn is_register(&self, name: &str) -> bool {
+        self.aliases.contains_key(name) || Register::try_from(name).is_ok()
+    }
+
+    /// The register `expression` names, if it is nothing but the name of one.
+    /// Used for operands that are either a register or a constant.
+    fn register_operand(
+        &self,
+        expression: &Pair<'i, Rule>,
+    ) -> Option<Result<Register, Diagnostic>> {
+        let mut inner = expression.clone().into_inner();
+        match (inner.next(), inner.next()) {
+            (Some(symbol), None)
+                if symbol.as_rule() == Rule::Symbol && self.is_register(symbol.as_str()) =>
+            {
+                Some(self.register(&symbol))
+            }
+            _ => None,
+        }
+    }
+
+    /// Works out the value of an `Expression`.
+    fn evaluate(&self, expression: &Pair<'i, Rule>) -> Result<i64, Diagnostic> {
+        Ok(self.value(expression)?.number)
+    }
+
+    /// `evaluate`, also working out how the value changes when the module is
+    /// linked after others.
+    fn value(&self, expression: &Pair<'i, Rule>) -> Result<Value, Diagnostic> {
+        self.evaluate_in(expression, &mut Vec::new())
+    }
+
+    /// `evaluate`, where `outer` are the constants whose value is being worked
+    /// out, to catch constants that are defined in terms of themselves.
+    fn evaluate_in(
+        &self,
+        expression: &Pair<'i, Rule>,
+        outer: &mut Vec<String>,
+    ) -> Result<Value, Diagnostic> {
+        let overflow = |expression: &Pair<Rule>| {
+            error(expression, format!("{} overflows", expression.as_str()))
+        };
+        OPERATORS
+            .map_primary(|primary| match primary.as_rule() {
+                Rule::Literal => parse_number(&primary).map(|number| Value {
+                    number,
+                    moves: Some(Moves::ZERO),
+                }),
+                Rule::Symbol => self.symbol(&primary, outer),
+                _ => self.evaluate_in(&primary, outer),
+            })
+            .map_prefix(move |op, value| {
+                let value = value?;
+                let number = match op.as_rule() {
+                    Rule::Neg => value
+                        .number
+                        .checked_neg()
+                        .ok_or_else(|| overflow(expression))?,
+                    _ => !value.number,
                 };
-                ProgramLine::Ins(action)
-            }
-            Rule::UnIns => {
-                let mut registers = ins.into_inner();
-                let action = registers.next().unwrap().as_str();
-                let reg: Register = registers.next().unwrap().as_str().try_into().unwrap();
-                let action = match action {
-                    "ZERO" => Instruction::Zero(reg),
-                    "INC" => Instruction::Inc(reg),
-                    "DEC" => Instruction::Dec(reg),
-                    "NOT" => Instruction::Not(reg),
-                    _ => unreachable!(),
+                // `~x` is `-x - 1`, so it moves the other way like `-x`.
+                let moves = value.moves.map(|moves| -moves);
+                Ok(Value { number, moves })
+            })
+            .map_infix(move |lhs, op, rhs| {
+                let (lhs, rhs) = (lhs?, rhs?);
+                let (a, b) = (lhs.number, rhs.number);
+                let shift = u32::try_from(b).unwrap_or(u32::MAX);
+                let number = match op.as_rule() {
+                    Rule::Add => a.checked_add(b),
+                    Rule::Sub => a.checked_sub(b),
+                    Rule::Mul => a.checked_mul(b),
+                    Rule::Div if b == 0 => {
+                        return Err(error(&op, "division by zero".to_string()));
+                    }
+                    Rule::Div => a.checked_div(b),
+                    Rule::Shl => a.checked_shl(shift),
+                    Rule::Shr => a.checked_shr(shift),
+                    Rule::BitAnd => Some(a & b),
+                    _ => Some(a | b),
                 };
-                ProgramLine::Ins(action)
-            }
-            Rule::MovIns => {
-                let mut registers = ins.into_inner();
-                let dest: Register = registers.next().unwrap().as_str().try_into().unwrap();
-                let src: Register = registers.next().unwrap().as_str().try_into().unwrap();
-                ProgramLine::Ins(Instruction::Mov(dest, src))
-            }
-            Rule::TriIns => {
-                let mut registers = ins.into_inner();
-                let action = registers.next().unwrap().as_str();
-                let dest: Register = registers.next().unwrap().as_str().try_into().unwrap();
-                let op1: Register = registers.next().unwrap().as_str().try_into().unwrap();
-                let op2: Register = registers.next().unwrap().as_str().try_into().unwrap();
-                let action = match action {
-                    "ADD" => Instruction::Add(dest, op1, op2),
-                    "SUB" => Instruction::Sub(dest, op1, op2),
-                    "AND" => Instruction::And(dest, op1, op2),
-                    "OR" => Instruction::Or(dest, op1, op2),
-                    "XOR" => Instruction::Xor(dest, op1, op2),
-                    _ => unreachable!(),
+                let moves = match (op.as_rule(), lhs.moves, rhs.moves) {
+                    (Rule::Add, Some(a), Some(b)) => Some(a + b),
+                    (Rule::Sub, Some(a), Some(b)) => Some(a - b),
+                    (Rule::Mul, Some(Moves::ZERO), Some(b)) => b.times(lhs.number),
+                    (Rule::Mul, Some(a), Some(Moves::ZERO)) => a.times(rhs.number),
+                    (_, Some(Moves::ZERO), Some(Moves::ZERO)) => Some(Moves::ZERO),
+                    _ => None,
                 };
-                ProgramLine::Ins(action)
-            }
-            Rule::ShiftIns => {
-                let mut registers = ins.into_inner();
-                let action = registers.next().unwrap().as_str();
-                let reg: Register = registers.next().unwrap().as_str().try_into().unwrap();
-                let k: u8 = registers.next().unwrap().as_str().parse().unwrap();
-                let action = match action {
-                    "SHL" => Instruction::Shl(reg, k),
-                    "SHR" => Instruction::Shr(reg, k),
-                    _ => unreachable!(),
+                let number = number.ok_or_else(|| overflow(expression))?;
+                Ok(Value { number, moves })
+            })
+            .parse(expression.clone().into_inner())
+    }
+
+    /// The value of a label or constant.
+    fn symbol(
+        &self,
+        symbol: &Pair<'i, Rule>,
+        outer: &mut Vec<String>,
+    ) -> Result<Value, Diagnostic> {
+        let name = symbol.as_str();
+        if let Some(address) = self.labels.get(name) {
+            let moves = self.data_labels.get(name).copied().unwrap_or(Moves::CODE);
+            return Ok(Value {
+                number: *address as i64,
+                moves: Some(moves),
+            });
+        }
+        let Some(expression) = self.constants.get(name) else {
+            let message = if self.is_register(name) {
+                format!("'{}' is a register, not a number", name)
+            } else {
+                format!("'{}' is not defined", name)
+            };
+            return Err(error(symbol, message));
+        };
+        if outer.iter().any(|constant| constant == name) {
+            let message = format!("'{}' is defined in terms of itself", name);
+            return Err(error(symbol, message));
+        }
+        outer.push(name.to_string());
+        let value = self.evaluate_in(expression, outer);
+        outer.pop();
+        value
+    }
+
+    /// Evaluates `expression` and checks that the value fits in a word.
+    fn word(&self, expression: &Pair<'i, Rule>) -> Result<u32, Diagnostic> {
+        let value = self.evaluate(expression)?;
+        let width = self.config.word_width;
+        if !width.fits(value) {
+            let size = sized(width.bits(), "word");
+            return Err(too_large(expression, value, &size));
+        }
+        Ok(width.wrap(value))
+    }
+
+    /// Evaluates `expression` as a memory address.
+    fn address(&self, expression: &Pair<'i, Rule>) -> Result<u32, Diagnostic> {
+        let value = self.evaluate(expression)?;
+        u32::try_from(value).map_err(|_| {
+            error(
+                expression,
+                format!("{} is not a valid memory address", value),
+            )
+        })
+    }
+
+    /// Evaluates `expression` as a number from 0 to 255. `message` says what
+    /// is wrong with any other value.
+    fn byte(
+        &self,
+        expression: &Pair<'i, Rule>,
+        message: fn(i64) -> String,
+    ) -> Result<u8, Diagnostic> {
+        let value = self.evaluate(expression)?;
+        u8::try_from(value).map_err(|_| error(expression, message(value)))
+    }
+}
+
+/// Says that the value of `expression` does not fit in `size`, like "a byte".
+fn too_large(expression: &Pair<Rule>, value: i64, size: &str) -> Diagnostic {
+    let mut inner = expression.clone().into_inner();
+    let message = match (inner.next().map(|pair| pair.as_rule()), inner.next()) {
+        (Some(Rule::Literal), None) => {
+            format!("literal {} does not fit in {}", expression.as_str(), size)
+        }
+        // A negative literal, which is `-` applied to a literal.
+        _ if expression.as_str() == value.to_string() => {
+            format!("literal {} does not fit in {}", value, size)
+        }
+        _ => format!(
+            "{} is {}, which does not fit in {}",
+            expression.as_str(),
+            value,
+            size
+        ),
+    };
+    error(expression, message)
+}
+
+/// Names a size in bits, like "an 8-bit word".
+pub(crate) fn sized(bits: u32, what: &str) -> String {
+    let article = match bits {
+        8 | 11 | 18 | 80..=89 => "an",
+        _ => "a",
+    };
+    format!("{} {}-bit {}", article, bits, what)
+}
+
+/// Keeps track of where data goes while reading a program from the top.
+#[derive(Default)]
+struct DataLayout {
+    /// Address of the next byte of data, or `None` outside the data section.
+    current: Option<usize>,
+    /// Where the data so far ends, which is where the next `.data` without
+    /// an address starts.
+    end: usize,
+    /// Whether the section was given an address, so its data stays there
+    /// when the module is linked after others.
+    fixed: bool,
+}
+
+impl DataLayout {
+    /// Follows a section or data directive, and returns the address of the
+    /// data for a data directive.
+    fn follow<'i>(
+        &mut self,
+        directive: &Pair<'i, Rule>,
+        scope: &Scope<'_, 'i>,
+    ) -> Result<Option<usize>, Diagnostic> {
+        match directive.as_rule() {
+            Rule::DataDirective => {
+                self.current = Some(self.end);
+                self.fixed = false;
+                if let Some(address) = directive.clone().into_inner().nth(1) {
+                    self.current = Some(scope.address(&address)? as usize);
+                    self.fixed = true;
+                }
+                Ok(None)
+            }
+            Rule::TextDirective => {
+                self.current = None;
+                Ok(None)
+            }
+            _ => {
+                let Some(address) = self.current else {
+                    return Err(error(
+                        directive,
+                        "data goes in the data section, add '.data' above it".to_string(),
+                    ));
                 };
-                ProgramLine::Ins(action)
+                let end = address + data_size(directive, scope)?;
+                let memory = scope.config.memory_size;
+                if end > memory {
+                    let message = format!(
+                        "data at {}..{} does not fit in the {} bytes of memory",
+                        address, end, memory
+                    );
+                    return Err(error(directive, message));
+                }
+                self.current = Some(end);
+                self.end = end;
+                Ok(Some(address))
+            }
+        }
+    }
+
+    /// How the addresses in the current section move when the module is
+    /// linked.
+    fn moves(&self) -> Moves {
+        match self.fixed {
+            true => Moves::ZERO,
+            false => Moves::DATA,
+        }
+    }
+
+    /// The relocation for `line`, which puts data or a data label at
+    /// `address` in the current section, if the section moves.
+    fn relocation(&self, line: usize, address: usize, span: &Span) -> Option<Relocation> {
+        (!self.fixed).then(|| Relocation {
+            lines: line..line + 1,
+            place: Place::Line,
+            value: address as i64,
+            moves: Some(Moves::DATA),
+            expression: String::new(),
+            span: span.clone(),
+        })
+    }
+}
+
+/// Number of bytes a data directive takes, worked out without the values of
+/// its operands, which may use labels that come later.
+fn data_size<'i>(directive: &Pair<'i, Rule>, scope: &Scope<'_, 'i>) -> Result<usize, Diagnostic> {
+    let mut operands = Operands::of(directive.clone());
+    let name = operands.next()?.as_str().to_lowercase();
+    match directive.as_rule() {
+        Rule::ValuesDirective if name == ".byte" => Ok(operands.inner.count()),
+        Rule::ValuesDirective => Ok(operands.inner.count() * scope.config.word_width.bytes()),
+        Rule::StringDirective => Ok(string_bytes(&operands.next()?)?.len() + 1),
+        _ => {
+            let size = operands.next()?;
+            let value = scope.evaluate(&size)?;
+            usize::try_from(value)
+                .map_err(|_| error(&size, format!("{} is not a valid size", value)))
+        }
+    }
+}
+
+/// The bytes of a `.byte`, `.word`, `.string`, `.asciz` or `.space`.
+/// Words are stored little endian, like `STORE` does.
+fn data_bytes<'i>(
+    directive: &Pair<'i, Rule>,
+    scope: &Scope<'_, 'i>,
+) -> Result<Vec<u8>, Diagnostic> {
+    let mut operands = Operands::of(directive.clone());
+    let name = operands.next()?.as_str().to_lowercase();
+    match directive.as_rule() {
+        Rule::ValuesDirective if name == ".byte" => operands
+            .inner
+            .map(|value| {
+                let byte = scope.evaluate(&value)?;
+                u8::try_from(byte)
+                    .or_else(|_| i8::try_from(byte).map(|byte| byte as u8))
+                    .map_err(|_| too_large(&value, byte, "a byte"))
+            })
+            .collect(),
+        Rule::ValuesDirective => {
+            let bytes = scope.config.word_width.bytes();
+            let mut data = Vec::new();
+            for value in operands.inner {
+                data.extend_from_slice(&scope.word(&value)?.to_le_bytes()[..bytes]);
+            }
+            Ok(data)
+        }
+        Rule::StringDirective => {
+            let mut bytes = string_bytes(&operands.next()?)?;
+            bytes.push(0);
+            Ok(bytes)
+        }
+        _ => Ok(vec![0; data_size(directive, scope)?]),
+    }
+}
+
+/// Parses one instruction. `ins` is any of the instruction rules of `Line`.
+fn parse_instruction<'i>(
+    ins: Pair<'i, Rule>,
+    scope: &Scope<'_, 'i>,
+) -> Result<Instruction, Diagnostic> {
+    let rule = ins.as_rule();
+    let mut operands = Operands::of(ins);
+    let name = operands.next()?;
+    let mnemonic = name.as_str().to_uppercase();
+    let instruction = match rule {
+        Rule::JumpIns => {
+            let label = operands.label()?;
+            match mnemonic.as_str() {
+                "J" => Instruction::J(label),
+                "JZ" => Instruction::Jz(label),
+                "JNZ" => Instruction::Jnz(label),
+                "JC" => Instruction::Jc(label),
+                "JNC" => Instruction::Jnc(label),
+                "JN" => Instruction::Jn(label),
+                "JV" => Instruction::Jv(label),
+                "JLT" => Instruction::Jlt(label),
+                "JGE" => Instruction::Jge(label),
+                "JB" => Instruction::Jb(label),
+                "JAE" => Instruction::Jae(label),
+                "CALL" => Instruction::Call(label),
+                _ => return Err(error(&name, unknown_mnemonic(&mnemonic))),
+            }
+        }
+        Rule::UnIns => {
+            let reg = operands.register(scope)?;
+            match mnemonic.as_str() {
+                "ZERO" => Instruction::Zero(reg),
+                "INC" => Instruction::Inc(reg),
+                "DEC" => Instruction::Dec(reg),
+                "NOT" => Instruction::Not(reg),
+                "PUSH" => Instruction::Push(reg),
+                "POP" => Instruction::Pop(reg),
+                _ => return Err(error(&name, unknown_mnemonic(&mnemonic))),
+            }
+        }
+        Rule::MovIns => {
+            let dest = operands.register(scope)?;
+            let src = operands.register(scope)?;
+            Instruction::Mov(dest, src)
+        }
+        Rule::TriIns => {
+            let dest = operands.register(scope)?;
+            let op1 = operands.register(scope)?;
+            let op2 = operands.register(scope)?;
+            match mnemonic.as_str() {
+                "ADD" => Instruction::Add(dest, op1, op2),
+                "ADC" => Instruction::Adc(dest, op1, op2),
+                "SUB" => Instruction::Sub(dest, op1, op2),
+                "SBC" => Instruction::Sbc(dest, op1, op2),
+                "AND" => Instruction::And(dest, op1, op2),
+                "OR" => Instruction::Or(dest, op1, op2),
+                "XOR" => Instruction::Xor(dest, op1, op2),
+                "MUL" => Instruction::Mul(dest, op1, op2),
+                "MULH" => Instruction::Mulh(dest, op1, op2),
+                "IMUL" => Instruction::Imul(dest, op1, op2),
+                "IMULH" => Instruction::Imulh(dest, op1, op2),
+                "DIV" => Instruction::Div(dest, op1, op2),
+                "MOD" => Instruction::Mod(dest, op1, op2),
+                "IDIV" => Instruction::Idiv(dest, op1, op2),
+                "IMOD" => Instruction::Imod(dest, op1, op2),
+                _ => return Err(error(&name, unknown_mnemonic(&mnemonic))),
+            }
+        }
+        Rule::ShiftIns => {
+            let reg = operands.register(scope)?;
+            let amount = operands.next()?;
+            match scope.register_operand(&amount) {
+                None => {
+                    let k = scope.byte(&amount, |amount| {
+                        format!("shift amount {} is too large", amount)
+                    })?;
+                    match mnemonic.as_str() {
+                        "SHL" => Instruction::Shl(reg, k),
+                        "SHR" => Instruction::Shr(reg, k),
+                        "SAR" => Instruction::Sar(reg, k),
+                        "ROL" => Instruction::Rol(reg, k),
+                        "ROR" => Instruction::Ror(reg, k),
+                        _ => return Err(error(&name, unknown_mnemonic(&mnemonic))),
+                    }
+                }
+                Some(k) => {
+                    let k = k?;
+                    match mnemonic.as_str() {
+                        "SHL" => Instruction::ShlR(reg, k),
+                        "SHR" => Instruction::ShrR(reg, k),
+                        "SAR" => Instruction::SarR(reg, k),
+                        "ROL" => Instruction::RolR(reg, k),
+                        "ROR" => Instruction::RorR(reg, k),
+                        _ => return Err(error(&name, unknown_mnemonic(&mnemonic))),
+                    }
+                }
+            }
+        }
+        Rule::RetIns => Instruction::Ret,
+        Rule::JrIns => Instruction::Jr(operands.register(scope)?),
+        Rule::LaIns => {
+            let reg = operands.register(scope)?;
+            Instruction::La(reg, operands.label()?)
+        }
+        Rule::HaltIns => match operands.next() {
+            Ok(register) => Instruction::HaltR(scope.register(&register)?),
+            Err(_) => Instruction::Halt,
+        },
+        Rule::InIns => {
+            let reg = operands.register(scope)?;
+            Instruction::In(reg, operands.port(scope)?)
+        }
+        Rule::OutIns => {
+            let port = operands.port(scope)?;
+            Instruction::Out(port, operands.register(scope)?)
+        }
+        Rule::MemIns => {
+            let reg = operands.register(scope)?;
+            let address = Operands::of(operands.next()?).next()?;
+            match scope.register_operand(&address) {
+                Some(address) => {
+                    let address = address?;
+                    match mnemonic.as_str() {
+                        "LOAD" => Instruction::Load(reg, address),
+                        "STORE" => Instruction::Store(reg, address),
+                        "LOADB" => Instruction::LoadB(reg, address),
+                        "STOREB" => Instruction::StoreB(reg, address),
+                        _ => return Err(error(&name, unknown_mnemonic(&mnemonic))),
+                    }
+                }
+                None => {
+                    let address = scope.address(&address)?;
+                    match mnemonic.as_str() {
+                        "LOAD" => Instruction::LoadI(reg, address),
+                        "STORE" => Instruction::StoreI(reg, address),
+                        "LOADB" => Instruction::LoadBI(reg, address),
+                        "STOREB" => Instruction::StoreBI(reg, address),
+                        _ => return Err(error(&name, unknown_mnemonic(&mnemonic))),
+                    }
+                }
+            }
+        }
+        Rule::SetIns => {
+            let reg = operands.register(scope)?;
+            let k = operands.word(scope)?;
+            Instruction::Set(reg, k)
+        }
+        Rule::ImmIns => {
+            let dest = operands.register(scope)?;
+            let op = operands.register(scope)?;
+            let k = operands.word(scope)?;
+            match mnemonic.as_str() {
+                "ADDI" => Instruction::AddI(dest, op, k),
+                "SUBI" => Instruction::SubI(dest, op, k),
+                "ANDI" => Instruction::AndI(dest, op, k),
+                "ORI" => Instruction::OrI(dest, op, k),
+                "XORI" => Instruction::XorI(dest, op, k),
+                _ => return Err(error(&name, unknown_mnemonic(&mnemonic))),
+            }
+        }
+        Rule::CmpIns => {
+            let left = operands.register(scope)?;
+            Instruction::Cmp(left, operands.register(scope)?)
+        }
+        Rule::CmpIIns => {
+            let reg = operands.register(scope)?;
+            let k = operands.word(scope)?;
+            Instruction::CmpI(reg, k)
+        }
+        _ => return Err(error(&name, unknown_mnemonic(&mnemonic))),
+    };
+    Ok(instruction)
+}
+
+/// Number of instructions that `ins` assembles to. Only pseudo-instructions
+/// take more than one, and how many is known from the operands the line has,
+/// so labels can be placed before any operand is evaluated.
+fn instruction_count(ins: &Pair<Rule>) -> usize {
+    match ins.as_rule() {
+        Rule::PseudoUnIns => match ins.clone().into_inner().next() {
+            Some(name) if name.as_str().eq_ignore_ascii_case("NEG") => 2,
+            _ => 1,
+        },
+        Rule::BranchIns => 2,
+        Rule::LiIns => ins
+            .clone()
+            .into_inner()
+            .filter(|operand| operand.as_rule() == Rule::register)
+            .count(),
+        _ => 1,
+    }
+}
+
+/// Parses an instruction or a pseudo-instruction into the instructions it
+/// stands for, which are:
+///
+/// - `NEG R1`: `NOT R1`, `INC R1`
+/// - `CLR R1`: `ZERO R1`
+/// - `BEQ R1, R2, label`: `CMP R1, R2`, `JZ label`, and `JNZ` for `BNE`
+/// - `LI R1, R2, k`: a `SET` for each register, with the lowest word of `k`
+///   in the first one, for constants that do not fit in one word
+/// - `NOP`: `MOV R0, R0`
+fn parse_instructions<'i>(
+    ins: Pair<'i, Rule>,
+    scope: &Scope<'_, 'i>,
+) -> Result<Vec<Instruction>, Diagnostic> {
+    let rule = ins.as_rule();
+    let mut operands = Operands::of(ins.clone());
+    let instructions = match rule {
+        Rule::PseudoUnIns => {
+            let name = operands.next()?.as_str().to_uppercase();
+            let reg = operands.register(scope)?;
+            match name.as_str() {
+                "NEG" => vec![Instruction::Not(reg), Instruction::Inc(reg)],
+                _ => vec![Instruction::Zero(reg)],
+            }
+        }
+        Rule::BranchIns => {
+            let name = operands.next()?.as_str().to_uppercase();
+            let left = operands.register(scope)?;
+            let right = operands.register(scope)?;
+            let jump = match name.as_str() {
+                "BEQ" => Instruction::Jz(operands.label()?),
+                _ => Instruction::Jnz(operands.label()?),
+            };
+            vec![Instruction::Cmp(left, right), jump]
+        }
+        Rule::LiIns => {
+            operands.next()?;
+            let mut registers = Vec::new();
+            let mut operand = operands.next()?;
+            while operand.as_rule() == Rule::register {
+                registers.push(scope.register(&operand)?);
+                operand = operands.next()?;
+            }
+            let value = scope.evaluate(&operand)?;
+            let bits = scope.config.word_width.bits() * registers.len() as u32;
+            if bits < 64 && !(-(1i64 << (bits - 1))..(1i64 << bits)).contains(&value) {
+                let size = match registers.len() {
+                    1 => sized(bits, "word"),
+                    _ => sized(bits, "number"),
+                };
+                return Err(too_large(&operand, value, &size));
+            }
+            let word = scope.config.word_width.bits();
+            registers
+                .into_iter()
+                .enumerate()
+                .map(|(n, reg)| {
+                    let shift = (word * n as u32).min(63);
+                    Instruction::Set(reg, scope.config.word_width.wrap(value >> shift))
+                })
+                .collect()
+        }
+        Rule::NopIns => vec![Instruction::Mov(Register(0), Register(0))],
+        _ => vec![parse_instruction(ins, scope)?],
+    };
+    Ok(instructions)
+}
+
+/// The operands of `ins` that are numbers, like the `k` of `SET R1, k` and
+/// `LOAD R1, [k]` or the values of a `.word`, each with where it goes.
+fn number_operands<'i>(
+    ins: &Pair<'i, Rule>,
+    scope: &Scope<'_, 'i>,
+) -> Vec<(Pair<'i, Rule>, Place)> {
+    let width = scope.config.word_width;
+    let place = match ins.as_rule() {
+        Rule::ValuesDirective => {
+            let mut operands = ins.clone().into_inner();
+            let width = match operands.next() {
+                Some(name) if name.as_str().eq_ignore_ascii_case(".byte") => WordWidth::W8,
+                _ => width,
+            };
+            return operands
+                .enumerate()
+                .map(|(n, value)| {
+                    let offset = n * width.bytes();
+                    (value, Place::Data { offset, width })
+                })
+                .collect();
+        }
+        Rule::SetIns | Rule::ImmIns | Rule::CmpIIns | Rule::LiIns => Place::Words(width),
+        Rule::MemIns => Place::Address,
+        Rule::ShiftIns => Place::Fixed("a shift amount"),
+        Rule::InIns | Rule::OutIns => Place::Fixed("a port"),
+        Rule::SpaceDirective => Place::Fixed("the size of data"),
+        Rule::DataDirective => Place::Fixed("the address of a data section"),
+        _ => return Vec::new(),
+    };
+    ins.clone()
+        .into_inner()
+        .filter_map(|operand| match operand.as_rule() {
+            Rule::Address => operand.into_inner().next(),
+            Rule::Expression => Some(operand),
+            _ => None,
+        })
+        // Registers, as in `SHL R1, R2`.
+        .filter(|operand| scope.register_operand(operand).is_none())
+        .map(|operand| (operand, place.clone()))
+        .collect()
+}
+
+/// Adds to `relocations` the operands of `ins` whose value changes when the
+/// module is linked after others. `lines` are the lines made from `ins`.
+fn relocate<'i>(
+    ins: &Pair<'i, Rule>,
+    scope: &Scope<'_, 'i>,
+    lines: Range<usize>,
+    source: &Expanded,
+    relocations: &mut Vec<Relocation>,
+) -> Result<(), Diagnostic> {
+    for (operand, place) in number_operands(ins, scope) {
+        let value = scope.value(&operand)?;
+        if value.moves != Some(Moves::ZERO) {
+            relocations.push(Relocation {
+                lines: lines.clone(),
+                place,
+                value: value.number,
+                moves: value.moves,
+                expression: operand.as_str().to_string(),
+                span: source.locate(&span_of(&operand)),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Describes what the grammar wanted where `rule` failed to match.
+fn describe(rule: Rule, config: &MachineConfig) -> String {
+    match rule {
+        Rule::register => format!("register {}", register_range(config)),
+        Rule::Literal
+        | Rule::DecLiteral
+        | Rule::HexLiteral
+        | Rule::BinLiteral
+        | Rule::CharLiteral
+        | Rule::Expression => "a number".to_string(),
+        Rule::identifier => "a label".to_string(),
+        Rule::Symbol => "a name".to_string(),
+        Rule::Address => "an address like [R1] or [16]".to_string(),
+        Rule::StringLiteral => "a string like \"text\"".to_string(),
+        Rule::EOI => "the end of the line".to_string(),
+        _ => "an instruction".to_string(),
+    }
+}
+
+/// Works out what is wrong with a line that the grammar did not accept.
+fn diagnose(line: &Pair<Rule>, config: &MachineConfig) -> Diagnostic {
+    let text = line.as_str().trim_end_matches(['\n', '\r']);
+    let (line_number, first_column) = line.line_col();
+    let at = |offset: usize, length: usize, message: String| {
+        let column = first_column + text[..offset].chars().count();
+        Diagnostic {
+            span: Span {
+                line: line_number,
+                column,
+                end_column: column + length,
+                ..Span::default()
+            },
+            message,
+        }
+    };
+    let word_at = |offset: usize| {
+        let rest = &text[offset..];
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == ',')
+            .unwrap_or(rest.len());
+        &rest[..end]
+    };
+
+    // Skip the labels in front of the statement.
+    let mut start = 0;
+    let mut word = word_at(start);
+    while let Some(label) = word.strip_suffix(':') {
+        let valid = |c: char| c.is_ascii_alphanumeric() || "_-.".contains(c);
+        if label.is_empty() {
+            return at(start, 1, "missing label name before ':'".to_string());
+        }
+        if !label.chars().all(valid) {
+            let message = format!(
+                "'{}' is not a valid label, labels only use letters, digits, '_', '-' and '.'",
+                label
+            );
+            return at(start, label.chars().count(), message);
+        }
+        let rest = &text[start + word.len()..];
+        start = text.len() - rest.trim_start().len();
+        word = word_at(start);
+    }
+    let example = MNEMONICS
+        .iter()
+        .find(|(mnemonic, _)| mnemonic.eq_ignore_ascii_case(word))
+        .map(|(mnemonic, operands)| format!("{} {}", mnemonic, operands));
+    if example.is_none() && !word.is_empty() && word.chars().all(char::is_alphabetic) {
+        return at(start, word.chars().count(), unknown_mnemonic(word));
+    }
+
+    let error = match ASMProgramParser::parse(Rule::Line, text) {
+        Ok(_) => return at(0, text.chars().count(), "unexpected text".to_string()),
+        Err(error) => error,
+    };
+    let column = match error.line_col {
+        LineColLocation::Pos((_, column)) | LineColLocation::Span((_, column), _) => column,
+    };
+    let offset = text
+        .char_indices()
+        .nth(column - 1)
+        .map_or(text.len(), |(offset, _)| offset);
+    // The grammar only reports where a rule failed, so a missing comma or
+    // operand shows up as the whole instruction failing. Show how it is
+    // written instead.
+    if let (true, Some(example)) = (offset <= start, example) {
+        let length = text[start..].trim_end().chars().count();
+        return at(
+            start,
+            length,
+            format!("malformed {}, write it like '{}'", word, example.trim_end()),
+        );
+    }
+    let found = match word_at(offset) {
+        "" => text[offset..]
+            .chars()
+            .next()
+            .map_or("", |c| &text[offset..offset + c.len_utf8()]),
+        word => word,
+    };
+    let found_text = match found {
+        "" => "the end of the line".to_string(),
+        found if found.starts_with('\'') => found.to_string(),
+        found => format!("'{}'", found),
+    };
+    let mut expected: Vec<String> = match error.variant {
+        ErrorVariant::ParsingError { positives, .. } => positives
+            .into_iter()
+            .map(|rule| describe(rule, config))
+            .collect(),
+        ErrorVariant::CustomError { .. } => Vec::new(),
+    };
+    expected.dedup();
+    let message = if expected.is_empty() {
+        format!("unexpected {}", found_text)
+    } else {
+        format!("expected {}, found {}", expected.join(" or "), found_text)
+    };
+    at(offset, found.chars().count(), message)
+}
+
+pub fn parse_program(file: &str) -> Result<Vec<ProgramLine>, ParseError> {
+    parse_program_with_config(file, &MachineConfig::default())
+}
+
+/// Parses a whole program. Every line is checked, so the error lists all
+/// the problems in the file instead of only the first one.
+pub fn parse_program_with_config(
+    file: &str,
+    config: &MachineConfig,
+) -> Result<Vec<ProgramLine>, ParseError> {
+    parse(file, None, config).map(|(lines, ..)| lines)
+}
+
+/// Like `parse_program_with_config`, but the spans of the lines and errors
+/// carry the name of the file `source` was read from, and `.include` finds
+/// files next to it instead of in the current directory.
+pub fn parse_file(
+    name: &str,
+    source: &str,
+    config: &MachineConfig,
+) -> Result<Vec<ProgramLine>, ParseError> {
+    parse(source, Some(Arc::from(name)), config).map(|(lines, ..)| lines)
+}
+
+/// Assembles one of the files of a program that is made of several, to be
+/// put together with `linker::link`. Labels that the file uses but does not
+/// define are left for the linker to find.
+pub fn parse_module(
+    name: &str,
+    source: &str,
+    config: &MachineConfig,
+) -> Result<Module, ParseError> {
+    let name: Arc<str> = Arc::from(name);
+    let (lines, sources, relocations) = parse(source, Some(name.clone()), config)?;
+    Ok(Module {
+        name,
+        lines,
+        sources,
+        relocations,
+    })
+}
+
+/// Assembles `file`, and also returns its sources and where the values that
+/// depend on labels are, for `parse_module`.
+fn parse(
+    file: &str,
+    name: Option<Arc<str>>,
+    config: &MachineConfig,
+) -> Result<(Vec<ProgramLine>, Sources, Vec<Relocation>), ParseError> {
+    let source = macros::expand(file, name);
+    let prg = ASMProgramParser::parse(Rule::Program, &source.text).map_err(|error| {
+        let (line, column) = match error.line_col {
+            LineColLocation::Pos(pos) | LineColLocation::Span(pos, _) => pos,
+        };
+        let span = Span {
+            line,
+            column,
+            end_column: column + 1,
+            ..Span::default()
+        };
+        ParseError {
+            diagnostics: vec![Diagnostic {
+                span: source.locate(&span),
+                message: error.variant.message().to_string(),
+            }],
+            sources: source.sources.clone(),
+        }
+    })?;
+    let lines: Vec<Pair<Rule>> = prg.collect();
+    let mut result: Vec<ProgramLine> = Vec::new();
+    let mut relocations: Vec<Relocation> = Vec::new();
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut scope = Scope {
+        config,
+        aliases: HashMap::new(),
+        labels: HashMap::new(),
+        data_labels: HashMap::new(),
+        constants: HashMap::new(),
+    };
+
+    let statements = || lines.iter().flat_map(|pair| pair.clone().into_inner());
+
+    // Constants and labels can be used before they are defined, so they are
+    // all collected first. Constants come first, as they can decide how much
+    // room data takes and so where the labels after it are.
+    let mut constant_names = HashMap::new();
+    for ins in statements().filter(|ins| ins.as_rule() == Rule::EquDirective) {
+        let mut parts = Operands::of(ins);
+        let (Ok(_), Ok(name), Ok(expression)) = (parts.next(), parts.next(), parts.next()) else {
+            continue;
+        };
+        if scope.constants.contains_key(name.as_str()) {
+            let message = format!("'{}' is already defined", name.as_str());
+            diagnostics.push(error(&name, message));
+            continue;
+        }
+        scope
+            .constants
+            .insert(name.as_str().to_string(), expression);
+        constant_names.insert(name.as_str(), name);
+    }
+    let mut address = 0;
+    let mut layout = DataLayout::default();
+    let mut label_names: HashMap<&str, Pair<Rule>> = HashMap::new();
+    for ins in statements() {
+        match ins.as_rule() {
+            Rule::label => {}
+            Rule::AliasDirective
+            | Rule::GlobalDirective
+            | Rule::EquDirective
+            | Rule::EOI
+            | Rule::BadLine => continue,
+            Rule::DataDirective
+            | Rule::TextDirective
+            | Rule::ValuesDirective
+            | Rule::StringDirective
+            | Rule::SpaceDirective => {
+                if let Err(diagnostic) = layout.follow(&ins, &scope) {
+                    diagnostics.push(diagnostic);
+                }
+                continue;
             }
-            _ => unreachable!(),
+            _ => {
+                address += instruction_count(&ins);
+                continue;
+            }
+        }
+        let Ok(label) = Operands::of(ins).next() else {
+            continue;
         };
-        result.push(line);
+        let name = label.as_str();
+        // Whichever of a constant and a label comes second is the mistake.
+        if let Some(constant) = constant_names.get(name) {
+            if constant.as_span().start() < label.as_span().start() {
+                diagnostics.push(error(&label, format!("'{}' is already defined", name)));
+                continue;
+            }
+            diagnostics.push(error(constant, format!("'{}' is already defined", name)));
+            scope.constants.remove(name);
+            constant_names.remove(name);
+        }
+        if let Some(first) = label_names.get(name) {
+            let first = source.locate(&span_of(first));
+            let message = format!("'{}' is already defined at {}", name, first);
+            diagnostics.push(error(&label, message));
+            continue;
+        }
+        match layout.current {
+            Some(data) => {
+                scope.data_labels.insert(name.to_string(), layout.moves());
+                scope.labels.insert(name.to_string(), data);
+            }
+            None => {
+                scope.labels.insert(name.to_string(), address);
+            }
+        }
+        label_names.insert(name, label);
+    }
+
+    let mut layout = DataLayout::default();
+    for pair in lines {
+        if pair.as_rule() == Rule::BadLine {
+            diagnostics.push(diagnose(&pair, config));
+            continue;
+        }
+        for ins in pair.into_inner() {
+            let span = source.locate(&span_of(&ins));
+
+            let line = match ins.as_rule() {
+                Rule::EOI => continue,
+                Rule::label => Operands::of(ins).label().map(|label| match layout.current {
+                    Some(data) => {
+                        relocations.extend(layout.relocation(result.len(), data, &span));
+                        Some(ProgramLine::DataLabel(label, data, span))
+                    }
+                    None => Some(ProgramLine::Lbl(label, span)),
+                }),
+                Rule::AliasDirective => {
+                    let mut parts = Operands::of(ins);
+                    parts.next().and_then(|_| {
+                        let Label(name) = parts.label()?;
+                        let register = parts.register(&scope)?;
+                        scope.aliases.insert(name.clone(), register);
+                        Ok(Some(ProgramLine::Alias(name, register, span)))
+                    })
+                }
+                Rule::GlobalDirective => {
+                    for label in ins.into_inner().skip(1) {
+                        let name = label.as_str();
+                        let message = if scope.data_labels.contains_key(name) {
+                            format!(
+                                "'{}' is a data label, only labels in the code can be exported",
+                                name
+                            )
+                        } else if scope.labels.contains_key(name) {
+                            let span = source.locate(&span_of(&label));
+                            result.push(ProgramLine::Global(Label(name.to_string()), span));
+                            continue;
+                        } else if scope.constants.contains_key(name) {
+                            format!("'{}' is a constant, only labels can be exported", name)
+                        } else {
+                            format!("'{}' is exported but never defined", name)
+                        };
+                        diagnostics.push(error(&label, message));
+                    }
+                    continue;
+                }
+                // Only checked here, the value is worked out where it is used.
+                Rule::EquDirective => {
+                    let mut parts = Operands::of(ins);
+                    parts
+                        .next()
+                        .and(parts.next())
+                        .and(parts.next())
+                        .and_then(|expression| scope.evaluate(&expression))
+                        .map(|_| None)
+                }
+                Rule::DataDirective | Rule::TextDirective => {
+                    layout.follow(&ins, &scope).and_then(|_| {
+                        let lines = result.len()..result.len();
+                        relocate(&ins, &scope, lines, &source, &mut relocations)?;
+                        Ok(None)
+                    })
+                }
+                Rule::ValuesDirective | Rule::StringDirective | Rule::SpaceDirective => {
+                    layout.follow(&ins, &scope).and_then(|address| {
+                        let bytes = data_bytes(&ins, &scope)?;
+                        let lines = result.len()..result.len() + 1;
+                        relocate(&ins, &scope, lines, &source, &mut relocations)?;
+                        Ok(address.map(|address| {
+                            relocations.extend(layout.relocation(result.len(), address, &span));
+                            ProgramLine::Data(address, bytes, span)
+                        }))
+                    })
+                }
+                _ if layout.current.is_some() => Err(error(
+                    &ins,
+                    "instructions cannot go in the data section, add '.text' above them"
+                        .to_string(),
+                )),
+                // A pseudo-instruction becomes several lines, which all
+                // point at it.
+                _ => parse_instructions(ins.clone(), &scope).and_then(|instructions| {
+                    for instruction in &instructions {
+                        if let Some(Label(label)) = instruction.label() {
+                            if scope.data_labels.contains_key(label) {
+                                return Err(error(
+                                    &ins,
+                                    format!("'{}' is a data label, not a place in the code", label),
+                                ));
+                            }
+                        }
+                    }
+                    let lines = result.len()..result.len() + instructions.len();
+                    relocate(&ins, &scope, lines, &source, &mut relocations)?;
+                    result.extend(
+                        instructions
+                            .into_iter()
+                            .map(|instruction| ProgramLine::Ins(instruction, span.clone())),
+                    );
+                    Ok(None)
+                }),
+            };
+            match line {
+                Ok(line) => result.extend(line),
+                Err(diagnostic) => diagnostics.push(diagnostic),
+            }
+        }
+    }
+    let mut diagnostics: Vec<Diagnostic> = diagnostics
+        .into_iter()
+        .map(|diagnostic| Diagnostic {
+            span: source.locate(&diagnostic.span),
+            ..diagnostic
+        })
+        .collect();
+    diagnostics.extend(source.diagnostics);
+    diagnostics.extend(overlapping_data(&result));
+    // A broken constant is reported where it is defined and again at every
+    // use, so only the first of those is kept. Problems inside macros are
+    // sorted by where the macro is called.
+    diagnostics.sort_by_key(|diagnostic| {
+        let mut call = &diagnostic.span;
+        while let Some(expansion) = &call.expansion {
+            call = &expansion.call;
+        }
+        (
+            call.line,
+            call.column,
+            diagnostic.span.line,
+            diagnostic.span.column,
+            diagnostic.message.clone(),
+        )
+    });
+    diagnostics.dedup();
+    if diagnostics.is_empty() {
+        Ok((result, source.sources, relocations))
+    } else {
+        Err(ParseError {
+            diagnostics,
+            sources: source.sources,
+        })
     }
-    Ok(result)
 }
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use crate::machine::{ErrorKind, Instruction, Location, ProgramError, ProgramLine, Register, Span};
+
+/// A program with its labels looked up, ready to be run by a `Machine`.
+///
+/// Instructions are stored by address, so labels and aliases take no room,
+/// and every jump already knows the address it goes to.
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedProgram {
+    instructions: Vec<Instruction>,
+    /// Where each instruction is in the source.
+    spans: Vec<Span>,
+    /// Address each instruction jumps to, or 0 for instructions without a label.
+    targets: Vec<usize>,
+    labels: HashMap<String, usize>,
+    aliases: HashMap<Register, Vec<String>>,
+    /// Bytes to put in memory before the program starts, by address.
+    data: Vec<(usize, Vec<u8>)>,
+    /// Labels of the `.data` section by address.
+    data_labels: Vec<(usize, String)>,
+}
+
+impl ResolvedProgram {
+    /// Lays out `program` and resolves every label it uses. Fails if a label
+    /// is defined twice or a jump goes to a label that is not defined.
+    pub fn resolve(program: Vec<ProgramLine>) -> Result<ResolvedProgram, ProgramError> {
+        let mut resolved = ResolvedProgram::default();
+        for line in program {
+            match line {
+                ProgramLine::Ins(instruction, span) => {
+                    resolved.instructions.push(instruction);
+                    resolved.spans.push(span);
+                }
+                ProgramLine::Lbl(label, _) => {
+                    let address = resolved.instructions.len();
+                    if resolved.labels.contains_key(&label.0) {
+                        return Err(ProgramError::new(ErrorKind::DuplicateLabel(label.0)));
+                    }
+                    resolved.labels.insert(label.0, address);
+                }
+                ProgramLine::Alias(name, register, _) => {
+                    resolved.aliases.entry(register).or_default().push(name)
+                }
+                ProgramLine::Global(..) => {}
+                ProgramLine::Data(address, bytes, _) => resolved.data.push((address, bytes)),
+                ProgramLine::DataLabel(label, address, _) => {
+                    resolved.data_labels.push((address, label.0))
+                }
+            }
+        }
+        resolved.targets = resolved
+            .instructions
+            .iter()
+            .enumerate()
+            .map(|(address, instruction)| match instruction.label() {
+                Some(label) => resolved
+                    .labels
+                    .get(&label.0)
+                    .copied()
+                    .ok_or_else(|| ProgramError {
+                        kind: ErrorKind::MissingLabel(label.0.clone()),
+                        location: Some(Location {
+                            address,
+                            span: resolved.spans[address].clone(),
+                            instruction: instruction.to_string(),
+                        }),
+                    }),
+                None => Ok(0),
+            })
+            .collect::<Result<_, _>>()?;
+        resolved.data_labels.sort();
+        Ok(resolved)
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Number of instructions.
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    /// Where the instruction at `address` is in the source.
+    pub fn span(&self, address: usize) -> &Span {
+        &self.spans[address]
+    }
+
+    /// Address the instruction at `address` jumps to.
+    pub fn target(&self, address: usize) -> usize {
+        self.targets[address]
+    }
+
+    /// Address of `label`, if it is defined.
+    pub fn label_address(&self, label: &str) -> Option<usize> {
+        self.labels.get(label).copied()
+    }
+
+    /// The program as it is laid out in memory: every instruction with its
+    /// address and where it came from, below the labels that point at it.
+    pub fn listing(&self) -> String {
+        let mut labels: Vec<(usize, &str)> = self
+            .labels
+            .iter()
+            .map(|(label, &address)| (address, label.as_str()))
+            .collect();
+        labels.sort();
+        let mut labels = labels.into_iter().peekable();
+        let mut listing = String::new();
+        for address in 0..=self.instructions.len() {
+            while let Some((_, label)) = labels.next_if(|&(at, _)| at == address) {
+                listing += &format!("      {}:\n", label);
+            }
+            if let Some(instruction) = self.instructions.get(address) {
+                let instruction = instruction.to_string();
+                listing += &format!(
+                    "{:04}  {:<24} ; {}\n",
+                    address, instruction, self.spans[address]
+                );
+            }
+        }
+        listing
+    }
+
+    /// The bytes of the `.data` section, with the address they go to.
+    pub fn data(&self) -> &[(usize, Vec<u8>)] {
+        &self.data
+    }
+
+    /// Labels of the `.data` section, ordered by address.
+    pub fn data_labels(&self) -> &[(usize, String)] {
+        &self.data_labels
+    }
+
+    /// Names given to each register with `.alias`.
+    pub fn aliases(&self) -> &HashMap<Register, Vec<String>> {
+        &self.aliases
+    }
+}
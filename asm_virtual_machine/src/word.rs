@@ -0,0 +1,80 @@
+use std::{fmt::Display, str::FromStr};
+
+/// Width of a machine word. Registers and ALU results are always reduced to
+/// this many bits, so arithmetic wraps around exactly like the real hardware.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WordWidth {
+    #[default]
+    W8,
+    W16,
+    W32,
+}
+
+impl WordWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            WordWidth::W8 => 8,
+            WordWidth::W16 => 16,
+            WordWidth::W32 => 32,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    /// All bits of a word set.
+    pub fn mask(self) -> u32 {
+        u32::MAX >> (32 - self.bits())
+    }
+
+    pub fn sign_bit(self) -> u32 {
+        1 << (self.bits() - 1)
+    }
+
+    /// Reduces `value` modulo 2^bits.
+    pub fn wrap(self, value: i64) -> u32 {
+        value as u32 & self.mask()
+    }
+
+    /// Reads `value` as a two's complement number.
+    pub fn to_signed(self, value: u32) -> i64 {
+        let value = (value & self.mask()) as i64;
+        if value & self.sign_bit() as i64 != 0 {
+            value - (1 << self.bits())
+        } else {
+            value
+        }
+    }
+
+    /// Whether `value` can be written into a word, either as a signed or an
+    /// unsigned number.
+    pub fn fits(self, value: i64) -> bool {
+        let bits = self.bits();
+        (-(1 << (bits - 1))..(1 << bits)).contains(&value)
+    }
+
+    /// Number of decimal digits needed to print any unsigned word.
+    pub fn decimal_digits(self) -> usize {
+        self.mask().to_string().len()
+    }
+}
+
+impl Display for WordWidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.bits())
+    }
+}
+
+impl FromStr for WordWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(WordWidth::W8),
+            "16" => Ok(WordWidth::W16),
+            "32" => Ok(WordWidth::W32),
+            _ => Err(format!("unsupported word width '{}', expected 8, 16 or 32", s)),
+        }
+    }
+}
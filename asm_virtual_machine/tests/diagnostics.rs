@@ -0,0 +1,277 @@
+//! Checks the errors `parse_program` reports for broken programs.
+
+use asm_virtual_machine::machine::Span;
+use asm_virtual_machine::parser::parse_program;
+
+/// The messages for `source`, each with the line and column it points at.
+fn messages(source: &str) -> Vec<(usize, usize, String)> {
+    parse_program(source)
+        .unwrap_err()
+        .diagnostics
+        .into_iter()
+        .map(|d| (d.span.line, d.span.column, d.message))
+        .collect()
+}
+
+#[test]
+fn reports_every_broken_line() {
+    let source = "SET R1, 5\nADDD R1, R2, R3\nloop:\nSET R9, 1\nMOV R1 R2\nJ loop\n";
+    assert_eq!(
+        messages(source),
+        vec![
+            (
+                2,
+                1,
+                "unknown mnemonic 'ADDD', did you mean 'ADD'?".to_string()
+            ),
+            (4, 5, "expected register R0–R7, found R9".to_string()),
+            (
+                5,
+                1,
+                "malformed MOV, write it like 'MOV R1, R2'".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn suggests_only_close_mnemonics() {
+    assert_eq!(
+        messages("foo\nSTOR R1, [R2]\n"),
+        vec![
+            (1, 1, "unknown mnemonic 'foo'".to_string()),
+            (
+                2,
+                1,
+                "unknown mnemonic 'STOR', did you mean 'STORE'?".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn points_at_the_bad_operand() {
+    assert_eq!(
+        messages("LOAD R1, R2\n"),
+        vec![(
+            1,
+            10,
+            "expected an address like [R1] or [16], found 'R2'".to_string()
+        )]
+    );
+    assert_eq!(
+        messages("SET R1, 300\n"),
+        vec![(
+            1,
+            9,
+            "literal 300 does not fit in an 8-bit word".to_string()
+        )]
+    );
+    assert_eq!(
+        messages("INC counter\n"),
+        vec![(
+            1,
+            5,
+            "expected register R0–R7 or an alias, found 'counter'".to_string()
+        )]
+    );
+}
+
+#[test]
+fn renders_the_source_line() {
+    let source = "SET R1, 1\n\tADDD R1, R2, R3\n";
+    let error = parse_program(source).unwrap_err();
+    assert_eq!(
+        error.diagnostics[0].span,
+        Span {
+            file: None,
+            line: 2,
+            column: 2,
+            end_column: 6,
+            expansion: None
+        }
+    );
+    assert_eq!(
+        error.render(source),
+        "error: unknown mnemonic 'ADDD', did you mean 'ADD'?\n \
+         --> 2:2\n  |\n2 | \tADDD R1, R2, R3\n  | \t^^^^\n"
+    );
+}
+
+#[test]
+fn never_panics() {
+    // Mangles a valid program one byte at a time.
+    let program = "start:\nSET R1, 'a'\n.alias x R2\nLOAD x, [0x10] ; load\nJNZ start\n\
+                   .macro M a, b\nm: ADD a, a, b\n.endm\nM R1, (2)\nHALT";
+    let noise = b" ,:;[]'\\\nR0x9-.aZ\xc3";
+    let mut state = 0x9e3779b97f4a7c15u64;
+    for _ in 0..2000 {
+        let mut bytes = program.as_bytes().to_vec();
+        for _ in 0..3 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let at = state as usize % bytes.len();
+            bytes[at] = noise[(state >> 32) as usize % noise.len()];
+        }
+        let source = String::from_utf8_lossy(&bytes);
+        if let Err(error) = parse_program(&source) {
+            error.render(&source);
+        }
+    }
+}
+
+#[test]
+fn explains_bad_constants() {
+    let source = "\
+.equ A, B + 1
+.equ B, A
+.equ C, 10 / (3 - 3)
+SET R1, NOPE
+SET R1, R2 + 1
+SET R1, 100 * 3
+SET R1, C
+.equ C, 2
+";
+    assert_eq!(
+        messages(source),
+        vec![
+            (1, 9, "'B' is defined in terms of itself".to_string()),
+            (2, 9, "'A' is defined in terms of itself".to_string()),
+            (3, 12, "division by zero".to_string()),
+            (4, 9, "'NOPE' is not defined".to_string()),
+            (5, 9, "'R2' is a register, not a number".to_string()),
+            (
+                6,
+                9,
+                "100 * 3 is 300, which does not fit in an 8-bit word".to_string()
+            ),
+            (8, 6, "'C' is already defined".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn labels_are_defined_once() {
+    let source = "\
+loop: INC R1
+loop: DEC R1
+.data
+loop: .byte 1
+";
+    assert_eq!(
+        messages(source),
+        vec![
+            (2, 1, "'loop' is already defined at line 1".to_string()),
+            (4, 1, "'loop' is already defined at line 1".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn errors_in_macros_show_the_call() {
+    let source = "\
+.macro SWAP a, b
+    XOR a, a, b
+.endm
+SWAP R1, R9
+SWAP R1
+";
+    assert_eq!(
+        messages(source),
+        vec![
+            (2, 15, "expected register R0–R7, found R9".to_string()),
+            (
+                5,
+                1,
+                "macro SWAP takes 2 arguments (a, b), found 1".to_string()
+            ),
+        ]
+    );
+    let error = parse_program(source).unwrap_err();
+    assert_eq!(
+        error.diagnostics[0].render(source),
+        "error: expected register R0–R7, found R9\n \
+         --> 2:15\n  |\n2 |     XOR a, a, b\n  |               ^\n\
+         note: in this call of macro SWAP\n \
+         --> 4:1\n  |\n4 | SWAP R1, R9\n  | ^^^^^^^^^^^\n"
+    );
+}
+
+#[test]
+fn explains_bad_data() {
+    let source = "\
+.byte 1
+.data
+buffer: .space 4
+        INC R1
+.data 2
+        .byte 1, 2
+        .word 1, 300
+.text
+        J buffer
+";
+    assert_eq!(
+        messages(source),
+        vec![
+            (
+                1,
+                1,
+                "data goes in the data section, add '.data' above it".to_string()
+            ),
+            (
+                4,
+                9,
+                "instructions cannot go in the data section, add '.text' above them".to_string()
+            ),
+            (
+                6,
+                9,
+                "data at 2..4 overlaps the data at 0..4 from line 3".to_string()
+            ),
+            (
+                7,
+                18,
+                "literal 300 does not fit in an 8-bit word".to_string()
+            ),
+            (
+                9,
+                9,
+                "'buffer' is a data label, not a place in the code".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn errors_in_pseudo_instructions_point_at_them() {
+    let source = "\
+        LI R1, 0x100
+        LI R1, R2, 0x12345
+        BEQ R1, R9, end
+        CMP R1
+.data
+end:    .byte 0
+";
+    assert_eq!(
+        messages(source),
+        vec![
+            (
+                1,
+                8,
+                "literal 0x100 does not fit in an 8-bit word".to_string()
+            ),
+            (
+                2,
+                20,
+                "literal 0x12345 does not fit in a 16-bit number".to_string()
+            ),
+            (3, 17, "expected register R0–R7, found R9".to_string()),
+            (
+                4,
+                9,
+                "malformed CMP, write it like 'CMP R1, R2'".to_string()
+            ),
+        ]
+    );
+}
@@ -0,0 +1,262 @@
+//! Runs random programs through both `Machine::step` and `Machine::run` and
+//! checks that they end in exactly the same state. `run` uses the bytecode
+//! engine, or the compiled code when the `jit` feature is on.
+
+use asm_virtual_machine::io::{InputQueue, OutputBuffer};
+use asm_virtual_machine::machine::{ErrorKind, Machine, MachineConfig, RunOutcome};
+use asm_virtual_machine::parser::parse_program_with_config;
+use asm_virtual_machine::word::WordWidth;
+
+/// Small xorshift generator, so the programs are the same on every run.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn register(&mut self) -> String {
+        // R7 is the loop counter and is never written by the random body.
+        format!("R{}", self.below(7))
+    }
+
+    fn literal(&mut self, width: WordWidth) -> String {
+        match self.below(4) {
+            0 => self.below(4).to_string(),
+            1 => format!("0x{:x}", self.next() as u32 & width.mask()),
+            2 => format!("-{}", self.below(width.sign_bit() as u64)),
+            _ => (self.next() as u32 & width.mask()).to_string(),
+        }
+    }
+}
+
+/// Generates a program that always terminates: a loop counted down in R7
+/// whose body only ever jumps forward.
+fn random_program(rng: &mut Rng, width: WordWidth) -> String {
+    let mut lines = vec![format!("SET R7, {}", 1 + rng.below(20)), "top:".to_string()];
+    let body = 5 + rng.below(25);
+    // Labels of forward jumps, with the number of lines left before each one.
+    let mut pending: Vec<(u64, String)> = Vec::new();
+    for n in 0..body {
+        for (left, label) in pending.iter_mut() {
+            if *left == 0 {
+                lines.push(format!("{}:", label));
+            }
+            *left = left.wrapping_sub(1);
+        }
+        pending.retain(|(left, _)| *left != u64::MAX);
+        let line = match rng.below(40) {
+            0 => format!("ZERO {}", rng.register()),
+            1 => format!("MOV {}, {}", rng.register(), rng.register()),
+            2..=13 => {
+                let name = [
+                    "ADD", "ADC", "SUB", "SBC", "AND", "OR", "XOR", "MUL", "MULH", "IMUL",
+                    "IMULH", "DIV", "MOD", "IDIV", "IMOD",
+                ][rng.below(15) as usize];
+                format!("{} {}, {}, {}", name, rng.register(), rng.register(), rng.register())
+            }
+            14 | 15 => {
+                let name = ["INC", "DEC", "NOT", "PUSH", "POP"][rng.below(5) as usize];
+                format!("{} {}", name, rng.register())
+            }
+            16 | 17 => {
+                let name = ["SHL", "SHR", "SAR", "ROL", "ROR"][rng.below(5) as usize];
+                if rng.below(2) == 0 {
+                    format!("{} {}, {}", name, rng.register(), rng.below(10))
+                } else {
+                    format!("{} {}, {}", name, rng.register(), rng.register())
+                }
+            }
+            18 | 19 => format!("SET {}, {}", rng.register(), rng.literal(width)),
+            20..=23 => {
+                let name = ["ADDI", "SUBI", "ANDI", "ORI", "XORI"][rng.below(5) as usize];
+                format!(
+                    "{} {}, {}, {}",
+                    name,
+                    rng.register(),
+                    rng.register(),
+                    rng.literal(width)
+                )
+            }
+            24 if rng.below(2) == 0 => format!("CMP {}, {}", rng.register(), rng.register()),
+            24 => format!("CMPI {}, {}", rng.register(), rng.literal(width)),
+            25 | 26 => {
+                let name = ["LOAD", "STORE", "LOADB", "STOREB"][rng.below(4) as usize];
+                if rng.below(2) == 0 {
+                    format!("{} {}, [{}]", name, rng.register(), rng.register())
+                } else {
+                    format!("{} {}, [{}]", name, rng.register(), rng.below(256))
+                }
+            }
+            27 => format!("IN {}, {}", rng.register(), rng.below(2)),
+            28 => format!("OUT {}, {}", rng.below(2), rng.register()),
+            29 => format!("LA {}, top", rng.register()),
+            30 if rng.below(4) == 0 => format!("HALT {}", rng.register()),
+            _ => {
+                let name = [
+                    "J", "JZ", "JNZ", "JC", "JNC", "JN", "JV", "JLT", "JGE", "JB", "JAE",
+                ][rng.below(11) as usize];
+                let label = format!("skip{}", n);
+                pending.push((rng.below(5), label.clone()));
+                format!("{} {}", name, label)
+            }
+        };
+        lines.push(line);
+    }
+    for (_, label) in pending {
+        lines.push(format!("{}:", label));
+    }
+    lines.push("DEC R7".to_string());
+    lines.push("JNZ top".to_string());
+    lines.join("\n") + "\n"
+}
+
+struct Run {
+    outcome: Option<RunOutcome>,
+    registers: String,
+    memory: String,
+    address: usize,
+    output: Vec<(u8, u32)>,
+}
+
+#[derive(Clone, Copy)]
+enum Limit {
+    None,
+    /// Take only this many steps. The outcome is `None` if the program is
+    /// still running after them.
+    Steps(u64),
+    /// Give the machine this step budget.
+    Budget(u64),
+}
+
+/// Runs `source` with `step`, or with `run` if `fast` is set.
+fn run_program(source: &str, width: WordWidth, fast: bool, limit: Limit) -> Run {
+    let config = MachineConfig {
+        word_width: width,
+        step_limit: match limit {
+            Limit::Budget(budget) => Some(budget),
+            _ => None,
+        },
+        ..MachineConfig::default()
+    };
+    let program = parse_program_with_config(source, &config).unwrap();
+    let mut machine = Machine::with_config(config);
+    let output = OutputBuffer::new();
+    machine.attach_device(0, Box::new(InputQueue::new([3, 1, 4, 1, 5, 9, 2, 6])));
+    machine.attach_device(1, Box::new(output.clone()));
+    machine.init_program(program).unwrap();
+    let steps = match limit {
+        Limit::Steps(steps) => steps,
+        _ => u64::MAX,
+    };
+    let outcome = if fast {
+        match limit {
+            Limit::Steps(steps) => machine.run_steps(steps),
+            _ => Some(machine.run()),
+        }
+    } else {
+        (0..steps)
+            .find_map(|_| machine.step().err())
+            .map(RunOutcome::from)
+    };
+    Run {
+        outcome,
+        registers: machine.get_string_registers(),
+        memory: machine.get_string_memory(),
+        address: machine.current_address(),
+        output: output.writes(),
+    }
+}
+
+fn compare_engines(seed: u64, width: WordWidth, programs: usize, limit: fn(u64) -> Limit) {
+    let mut rng = Rng(seed);
+    for _ in 0..programs {
+        let source = random_program(&mut rng, width);
+        let limit = limit(rng.below(400));
+        let slow = run_program(&source, width, false, limit);
+        let fast = run_program(&source, width, true, limit);
+        assert_eq!(slow.outcome, fast.outcome, "outcome differs for\n{}", source);
+        assert_eq!(slow.registers, fast.registers, "registers differ for\n{}", source);
+        assert_eq!(slow.memory, fast.memory, "memory differs for\n{}", source);
+        assert_eq!(slow.address, fast.address, "address differs for\n{}", source);
+        assert_eq!(slow.output, fast.output, "output differs for\n{}", source);
+    }
+}
+
+#[test]
+fn engines_agree_on_8_bit_words() {
+    compare_engines(0x9e3779b97f4a7c15, WordWidth::W8, 500, |_| Limit::None);
+}
+
+#[test]
+fn engines_agree_on_16_bit_words() {
+    compare_engines(0xdeadbeefcafef00d, WordWidth::W16, 500, |_| Limit::None);
+}
+
+#[test]
+fn engines_agree_on_32_bit_words() {
+    compare_engines(0x0123456789abcdef, WordWidth::W32, 500, |_| Limit::None);
+}
+
+#[test]
+fn engines_stop_at_the_same_step() {
+    compare_engines(0x2545f4914f6cdd1d, WordWidth::W8, 300, Limit::Steps);
+    compare_engines(0x5851f42d4c957f2d, WordWidth::W32, 300, Limit::Steps);
+}
+
+#[test]
+fn engines_agree_on_step_budget() {
+    compare_engines(0x61c8864680b583eb, WordWidth::W8, 300, Limit::Budget);
+    compare_engines(0xbf58476d1ce4e5b9, WordWidth::W16, 300, Limit::Budget);
+}
+
+#[test]
+fn loop_detector_leaves_terminating_programs_alone() {
+    let mut rng = Rng(0x94d049bb133111eb);
+    for _ in 0..300 {
+        let source = random_program(&mut rng, WordWidth::W8);
+        let config = MachineConfig {
+            detect_loops: true,
+            ..MachineConfig::default()
+        };
+        let program = parse_program_with_config(&source, &config).unwrap();
+        let mut machine = Machine::with_config(config);
+        machine.attach_device(0, Box::new(InputQueue::new([3, 1, 4, 1, 5, 9, 2, 6])));
+        machine.attach_device(1, Box::new(OutputBuffer::new()));
+        machine.init_program(program).unwrap();
+        let detected = machine.run();
+        let plain = run_program(&source, WordWidth::W8, true, Limit::None);
+        assert_eq!(Some(detected), plain.outcome, "outcome differs for\n{}", source);
+    }
+}
+
+#[test]
+fn loop_detector_finds_loops() {
+    let source = "SET R1, 5\nINC R2\nloop:\nADDI R1, R1, 1\nSUBI R1, R1, 1\nJ loop\n";
+    let config = MachineConfig {
+        detect_loops: true,
+        ..MachineConfig::default()
+    };
+    let mut machine = Machine::with_config(config.clone());
+    machine
+        .init_program(parse_program_with_config(source, &config).unwrap())
+        .unwrap();
+    let RunOutcome::Error(error) = machine.run() else {
+        panic!("loop not detected");
+    };
+    assert_eq!(
+        error.kind,
+        ErrorKind::InfiniteLoop {
+            first: 2,
+            last: 4,
+            period: 3
+        }
+    );
+}
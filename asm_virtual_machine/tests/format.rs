@@ -0,0 +1,143 @@
+//! Checks that the formatter lays programs out the canonical way and never
+//! changes what they do.
+
+use asm_virtual_machine::format::format_program;
+use asm_virtual_machine::machine::Machine;
+use asm_virtual_machine::parser::parse_program;
+
+#[test]
+fn programs_are_laid_out_the_same_way() {
+    let source = "\
+; Sums the numbers from 5 down to 1
+\t.ALIAS  cnt   r2   # the running total\r
+.equ   BASE,0x10
+.define COUNT   3
+
+
+.macro  swap a b
+  PUSH a
+   mov a,b // copy
+  pop b
+.endm
+set r1,5
+loop:\tadd cnt ,cnt,  R1 ; it's ü-nicode
+  jnz loop
+a: b: ADD r3 r3 r1
+ store r1 , [ BASE + 2 ]
+   load r3 , [ r2 ]
+  swap R1, r3
+     ; indented note
+.data
+msg:   .asciz \"a; b\"    ; text
+  .BYTE 1,2 ,  ';'
+";
+    let formatted = format_program(source, &Default::default()).unwrap();
+    assert_eq!(
+        formatted,
+        "\
+; Sums the numbers from 5 down to 1
+.alias cnt R2  ; the running total
+.equ BASE, 0x10
+.define COUNT 3
+
+.macro swap a, b
+        PUSH    a
+        MOV     a, b      ; copy
+        POP     b
+.endm
+    SET     R1, 5
+loop:
+    ADD     cnt, cnt, R1  ; it's ü-nicode
+    JNZ     loop
+a:
+b:
+    ADD     R3, R3, R1
+    STORE   R1, [BASE + 2]
+    LOAD    R3, [R2]
+    swap    R1, R3
+    ; indented note
+.data
+msg:
+    .asciz  \"a; b\"        ; text
+    .byte   1, 2, ';'
+"
+    );
+    assert_eq!(
+        format_program(&formatted, &Default::default()).unwrap(),
+        formatted
+    );
+
+    let instructions = |source: &str| -> Vec<String> {
+        let mut machine = Machine::new();
+        machine
+            .init_program(parse_program(source).unwrap())
+            .unwrap();
+        let instructions = machine.program().instructions();
+        instructions.iter().map(ToString::to_string).collect()
+    };
+    assert_eq!(instructions(source), instructions(&formatted));
+}
+
+#[test]
+fn names_that_look_like_registers_are_kept() {
+    let source = "\
+.alias r7 R1
+.equ r6, 2
+r5: SET r7, r6
+    LA r4, r5
+";
+    assert_eq!(
+        format_program(source, &Default::default()).unwrap(),
+        "\
+.alias r7 R1
+.equ r6, 2
+r5:
+    SET     r7, r6
+    LA      R4, r5
+"
+    );
+}
+
+#[test]
+fn programs_with_errors_are_left_alone() {
+    let error = format_program("ADDD R1\n", &Default::default()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "line 1, column 1: unknown mnemonic 'ADDD', did you mean 'ADD'?"
+    );
+}
+
+#[test]
+fn expressions_and_macro_calls_are_read_like_the_assembler_does() {
+    let source = "\
+.equ SIZE,(4*2)-1
+.macro JUMP to
+J to
+.endm
+my-label: SET R1,~SIZE&0x0f # mask
+JUMP my-label   // a label with a '-'
+";
+    assert_eq!(
+        format_program(source, &Default::default()).unwrap(),
+        "\
+.equ SIZE, (4 * 2) - 1
+.macro JUMP to
+        J       to
+.endm
+my-label:
+    SET     R1, ~SIZE & 0x0f  ; mask
+    JUMP    my-label          ; a label with a '-'
+"
+    );
+}
+
+#[test]
+fn formatting_twice_changes_nothing() {
+    let source = "SET R1, 8 / 2 // half\nSET R2,(6/ 3)//third\n";
+    let once = format_program(source, &Default::default()).unwrap();
+    assert_eq!(
+        once,
+        "    SET     R1, 8 / 2    ; half\n    SET     R2, (6 / 3)  ;third\n"
+    );
+    assert_eq!(format_program(&once, &Default::default()).unwrap(), once);
+}
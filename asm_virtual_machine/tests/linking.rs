@@ -0,0 +1,251 @@
+//! Checks programs that are split over several files, with `.include` and
+//! with modules that are linked together.
+
+use std::{fs, path::PathBuf};
+
+use asm_virtual_machine::linker::link;
+use asm_virtual_machine::machine::{ErrorKind, Machine, ProgramLine, RunOutcome};
+use asm_virtual_machine::parser::{parse_file, parse_module};
+
+/// Writes `files` to a new directory and returns its path.
+fn directory(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("comarc-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    for (file, text) in files {
+        let path = directory.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    }
+    directory
+}
+
+fn run(program: Vec<ProgramLine>) -> RunOutcome {
+    let mut machine = Machine::new();
+    machine.init_program(program).unwrap();
+    machine.run()
+}
+
+#[test]
+fn includes_are_read_next_to_the_file() {
+    let directory = directory(
+        "include",
+        &[
+            (
+                "main.asm",
+                ".include \"lib/double.asm\"\nSET R1, 4\nDOUBLE R1\nHALT R1\n",
+            ),
+            (
+                "lib/double.asm",
+                ".macro DOUBLE r\n    ADD r, r, r\n.endm\n",
+            ),
+        ],
+    );
+    let main = directory.join("main.asm");
+    let name = main.to_string_lossy();
+    let program = parse_file(
+        &name,
+        &fs::read_to_string(&main).unwrap(),
+        &Default::default(),
+    );
+    let program = program.unwrap();
+    assert_eq!(run(program), RunOutcome::Halted(8));
+
+    let source = ".include \"loop.asm\"\n.include \"nowhere.asm\"\n";
+    let path = directory.join("lib/loop.asm");
+    fs::write(&path, source).unwrap();
+    let error = parse_file(&path.to_string_lossy(), source, &Default::default()).unwrap_err();
+    let messages: Vec<&str> = error
+        .diagnostics
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(messages.len(), 2);
+    assert!(
+        messages[0].ends_with("loop.asm' includes itself"),
+        "{}",
+        messages[0]
+    );
+    assert!(messages[1].starts_with("cannot read"), "{}", messages[1]);
+}
+
+#[test]
+fn modules_share_only_what_they_export() {
+    let main = "\
+.global done
+        SET R1, 3
+loop:   CALL triple
+        J done
+done:   HALT R1
+";
+    let lib = "\
+.global triple
+triple: ADD R2, R1, R1
+        ADD R1, R2, R1
+loop:   RET
+";
+    let modules = vec![
+        parse_module("main.asm", main, &Default::default()).unwrap(),
+        parse_module("lib.asm", lib, &Default::default()).unwrap(),
+    ];
+    let program = link(modules).unwrap();
+    let labels: Vec<&str> = program
+        .iter()
+        .filter_map(|line| match line {
+            ProgramLine::Lbl(label, _) => Some(label.0.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(labels, ["loop@main.asm", "done", "triple", "loop@lib.asm"]);
+    assert_eq!(run(program), RunOutcome::Halted(9));
+}
+
+#[test]
+fn link_errors_name_the_symbol() {
+    let a = ".global f\nf: CALL g\n   CALL h\n";
+    let b = ".global f\nf: RET\nh: RET\n";
+    let modules = vec![
+        parse_module("a.asm", a, &Default::default()).unwrap(),
+        parse_module("b.asm", b, &Default::default()).unwrap(),
+    ];
+    let error = link(modules).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "b.asm:1:9: 'f' is already exported at a.asm:1:9\n\
+         a.asm:2:4: 'g' is not defined in any module\n\
+         a.asm:3:4: 'h' is defined in b.asm but not exported, add '.global h' there"
+    );
+    assert!(error.render("").contains("2 | f: CALL g\n"));
+}
+
+#[test]
+fn labels_are_defined_once_in_a_program() {
+    let directory = directory(
+        "duplicate",
+        &[
+            ("main.asm", ".include \"lib.asm\"\nfoo: HALT\n"),
+            ("lib.asm", "foo: RET\n"),
+        ],
+    );
+    let main = directory.join("main.asm");
+    let name = main.to_string_lossy();
+    let source = fs::read_to_string(&main).unwrap();
+    let error = parse_file(&name, &source, &Default::default()).unwrap_err();
+    assert_eq!(error.diagnostics.len(), 1);
+    let message = &error.diagnostics[0].message;
+    assert!(
+        message.starts_with("'foo' is already defined at "),
+        "{}",
+        message
+    );
+    assert!(message.ends_with("lib.asm:1:1"), "{}", message);
+
+    // Lines put together by hand are checked when they are loaded.
+    let mut lines = parse_file("a.asm", "foo: RET\n", &Default::default()).unwrap();
+    lines.extend(parse_file("b.asm", "foo: HALT\n", &Default::default()).unwrap());
+    let error = Machine::new().init_program(lines).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::DuplicateLabel("foo".to_string()));
+}
+
+#[test]
+fn label_values_move_with_their_module() {
+    let main = "\
+        CALL f
+        ADD R1, R1, R3
+        HALT R1
+";
+    let lib = "\
+.global f
+.data
+table:  .word back
+.text
+f:      SET R1, back        ; the same as LA
+        LA R2, back
+        SUB R1, R1, R2
+        LOAD R4, [table]    ; and so is the jump table
+        SUB R4, R4, R2
+        ADD R1, R1, R4
+        SET R3, 2 * back - f
+back:   RET
+";
+    let modules = vec![
+        parse_module("main.asm", main, &Default::default()).unwrap(),
+        parse_module("lib.asm", lib, &Default::default()).unwrap(),
+    ];
+    // back is at 3 + 7 and f at 3.
+    assert_eq!(run(link(modules).unwrap()), RunOutcome::Halted(17));
+
+    let lib = "\
+.global f
+f:      SET R1, f * f
+        SHL R1, f
+        SET R1, f + 253
+        SET R1, f - f
+";
+    let modules = vec![
+        parse_module("main.asm", main, &Default::default()).unwrap(),
+        parse_module("lib.asm", lib, &Default::default()).unwrap(),
+    ];
+    let messages: Vec<String> = link(modules)
+        .unwrap_err()
+        .diagnostics
+        .into_iter()
+        .map(|diagnostic| format!("{}: {}", diagnostic.span, diagnostic.message))
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            "lib.asm:2:17: f * f cannot be worked out once lib.asm is linked after other modules, only sums and differences of labels can",
+            "lib.asm:3:17: f changes when lib.asm is linked after other modules, so it cannot be a shift amount",
+            "lib.asm:4:17: f + 253 is 256 once lib.asm is linked, which does not fit in an 8-bit word",
+        ]
+    );
+    // First, the module does not move.
+    let modules = vec![parse_module("lib.asm", lib, &Default::default()).unwrap()];
+    assert!(link(modules).is_ok());
+}
+
+#[test]
+fn each_module_has_its_own_data() {
+    let main = "\
+.data
+first:  .byte 5
+.text
+        LOADB R1, [first]
+        CALL value
+        ADD R1, R1, R2
+        HALT R1
+";
+    let lib = "\
+.global value
+.data
+count:  .byte 7
+pointer: .byte count
+.data 0x80
+fixed:  .byte 30
+.text
+value:  LOADB R3, [pointer]
+        LOADB R2, [R3]
+        LOADB R4, [fixed]
+        ADD R2, R2, R4
+        RET
+";
+    let modules = vec![
+        parse_module("main.asm", main, &Default::default()).unwrap(),
+        parse_module("lib.asm", lib, &Default::default()).unwrap(),
+    ];
+    let program = link(modules).unwrap();
+    let data: Vec<(usize, Vec<u8>)> = program
+        .iter()
+        .filter_map(|line| match line {
+            ProgramLine::Data(address, bytes, _) => Some((*address, bytes.clone())),
+            _ => None,
+        })
+        .collect();
+    // The data of lib.asm goes after the data of main.asm, except what was
+    // given an address.
+    assert_eq!(
+        data,
+        [(0, vec![5]), (1, vec![7]), (2, vec![1]), (0x80, vec![30])]
+    );
+    assert_eq!(run(program), RunOutcome::Halted(42));
+}
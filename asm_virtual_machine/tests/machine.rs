@@ -0,0 +1,422 @@
+//! Runs small programs and checks the state they leave the machine in.
+
+use asm_virtual_machine::io::{InputQueue, OutputBuffer};
+use asm_virtual_machine::machine::{
+    ErrorKind, Flags, Machine, MachineConfig, ProgramError, ProgramLine, Register, RunOutcome,
+};
+use asm_virtual_machine::parser::{parse_program, parse_program_with_config};
+use asm_virtual_machine::program::ResolvedProgram;
+use asm_virtual_machine::word::WordWidth;
+
+const WIDTHS: [WordWidth; 3] = [WordWidth::W8, WordWidth::W16, WordWidth::W32];
+
+/// The default machine, with words of `width`.
+fn config(width: WordWidth) -> MachineConfig {
+    MachineConfig {
+        word_width: width,
+        ..MachineConfig::default()
+    }
+}
+
+/// Runs `source` until it stops, and returns the machine with why it stopped.
+fn run(source: &str, config: MachineConfig) -> (Machine, ProgramError) {
+    let program = parse_program_with_config(source, &config).unwrap();
+    let mut machine = Machine::with_config(config);
+    machine.init_program(program).unwrap();
+    let stopped = finish(&mut machine);
+    (machine, stopped)
+}
+
+/// Steps `machine` until it stops, and returns why.
+fn finish(machine: &mut Machine) -> ProgramError {
+    loop {
+        if let Err(error) = machine.step() {
+            break error;
+        }
+    }
+}
+
+/// Flags with the ones named in `set`, like `"ZC"`, set.
+fn flags(set: &str) -> Flags {
+    Flags {
+        zero: set.contains('Z'),
+        carry: set.contains('C'),
+        negative: set.contains('N'),
+        overflow: set.contains('V'),
+    }
+}
+
+#[test]
+fn memory_ends_where_it_ends() {
+    // 256 bytes of memory, so the last word starts 4 bytes before the end.
+    let source = "SET R1, 4660\nSTORE R1, [252]\nLOAD R2, [252]\n";
+    let (machine, stopped) = run(source, config(WordWidth::W32));
+    assert_eq!(stopped.kind, ErrorKind::EndOfProgram);
+    assert_eq!(machine.register(&Register(2)), 4660);
+    assert_eq!(machine.memory()[252..], [0x34, 0x12, 0, 0]);
+
+    let (_, stopped) = run("SET R1, 253\nLOAD R2, [R1]\n", config(WordWidth::W32));
+    assert_eq!(stopped.kind, ErrorKind::MemoryOutOfBounds(253));
+    let (_, stopped) = run("SET R1, 255\nSTORE R1, [R1]\n", config(WordWidth::W16));
+    assert_eq!(stopped.kind, ErrorKind::MemoryOutOfBounds(255));
+    let small = MachineConfig {
+        memory_size: 16,
+        ..MachineConfig::default()
+    };
+    let (_, stopped) = run("SET R1, 15\nSTORE R1, [R1]\nLOAD R2, [16]\n", small);
+    assert_eq!(stopped.kind, ErrorKind::MemoryOutOfBounds(16));
+}
+
+#[test]
+fn stack_and_calls_have_limits() {
+    // The 64 bytes of stack hold 16 words of 32 bits, or 64 of 8 bits.
+    let pushes = "loop:\nINC R1\nPUSH R1\nJ loop\n";
+    for (width, words) in [(WordWidth::W32, 16), (WordWidth::W8, 64)] {
+        let (machine, stopped) = run(pushes, config(width));
+        assert_eq!(stopped.kind, ErrorKind::StackOverflow, "at {}", width);
+        assert_eq!(machine.register(&Register(1)), words + 1, "at {}", width);
+    }
+    let (machine, stopped) = run("INC R1\nPUSH R1\nPOP R2\nPOP R3\n", config(WordWidth::W32));
+    assert_eq!(stopped.kind, ErrorKind::StackUnderflow);
+    assert_eq!(machine.register(&Register(2)), 1);
+
+    let (machine, stopped) = run("f:\nINC R1\nCALL f\n", config(WordWidth::W32));
+    assert_eq!(stopped.kind, ErrorKind::CallStackOverflow(64));
+    assert_eq!(stopped.kind.to_string(), "call depth of 64 exceeded");
+    assert_eq!(machine.register(&Register(1)), 65);
+    let source = "CALL f\nINC R2\nRET\nf:\nINC R1\nRET\n";
+    let (machine, stopped) = run(source, config(WordWidth::W32));
+    assert_eq!(stopped.kind, ErrorKind::EmptyCallStack);
+    assert_eq!(machine.register(&Register(1)), 1);
+    assert_eq!(machine.register(&Register(2)), 1);
+}
+
+#[test]
+fn carry_and_overflow_at_every_width() {
+    for width in WIDTHS {
+        let max = width.mask();
+        let sign = width.sign_bit();
+        let run = |source: &str| run(&format!("SET R2, 1\nSET R5, 5\n{}", source), config(width));
+
+        // The carry out of the top bit is added in by ADC.
+        let (machine, _) = run(&format!("SET R1, {}\nADD R3, R1, R2\n", max));
+        assert_eq!(machine.register(&Register(3)), 0, "at {}", width);
+        assert_eq!(machine.flags(), flags("ZC"), "at {}", width);
+        let (machine, _) = run(&format!(
+            "SET R1, {}\nADD R3, R1, R2\nADC R4, R0, R0\n",
+            max
+        ));
+        assert_eq!(machine.register(&Register(4)), 1, "at {}", width);
+        assert_eq!(machine.flags(), flags(""), "at {}", width);
+
+        // After a subtraction the carry is the borrow, and SBC takes it off.
+        let (machine, _) = run("SUB R3, R0, R2\n");
+        assert_eq!(machine.register(&Register(3)), max, "at {}", width);
+        assert_eq!(machine.flags(), flags("CN"), "at {}", width);
+        let (machine, _) = run("SUB R3, R0, R2\nSBC R4, R5, R0\n");
+        assert_eq!(machine.register(&Register(4)), 4, "at {}", width);
+        assert_eq!(machine.flags(), flags(""), "at {}", width);
+        let (machine, _) = run("SUB R3, R5, R2\n");
+        assert_eq!(machine.flags(), flags(""), "at {}", width);
+
+        // Signed overflow, without a carry, and both at once for MIN + MIN.
+        let (machine, _) = run(&format!("SET R1, {}\nADD R3, R1, R2\n", sign - 1));
+        assert_eq!(machine.register(&Register(3)), sign, "at {}", width);
+        assert_eq!(machine.flags(), flags("NV"), "at {}", width);
+        let (machine, _) = run(&format!("SET R1, {}\nSUB R3, R1, R2\n", sign));
+        assert_eq!(machine.register(&Register(3)), sign - 1, "at {}", width);
+        assert_eq!(machine.flags(), flags("V"), "at {}", width);
+        let (machine, _) = run(&format!("SET R1, {}\nADD R3, R1, R1\n", sign));
+        assert_eq!(machine.flags(), flags("ZCV"), "at {}", width);
+    }
+}
+
+#[test]
+fn words_wrap_at_their_width() {
+    for width in WIDTHS {
+        let max = width.mask();
+        let source = format!(
+            "SET R1, -1\nSET R2, {}\nINC R2\nDEC R3\nSTORE R1, [0]\n",
+            max
+        );
+        let (machine, _) = run(&source, config(width));
+        assert_eq!(machine.register(&Register(1)), max, "at {}", width);
+        assert_eq!(machine.register(&Register(2)), 0, "at {}", width);
+        assert_eq!(machine.register(&Register(3)), max, "at {}", width);
+        // A word takes as many bytes of memory as it has.
+        let bytes = width.bytes();
+        assert_eq!(machine.memory()[..bytes], vec![0xff; bytes], "at {}", width);
+        assert_eq!(machine.memory()[bytes], 0, "at {}", width);
+    }
+}
+
+#[test]
+fn machines_have_as_many_registers_as_configured() {
+    let config = MachineConfig {
+        register_count: 16,
+        ..MachineConfig::default()
+    };
+    let source = ".alias acc R15\nSET acc, 7\nMOV R12, acc\n";
+    assert!(parse_program(source).is_err());
+    let (mut machine, _) = run(source, config.clone());
+    assert_eq!(machine.register(&Register(12)), 7);
+    assert_eq!(machine.register(&Register(15)), 7);
+    assert_eq!(machine.register_count(), 16);
+    assert!(machine
+        .get_string_registers()
+        .contains("Register: R15 (acc) = "));
+
+    assert!(parse_program_with_config("INC R16\n", &config).is_err());
+    assert!(parse_program_with_config(".alias acc R16\n", &config).is_err());
+    assert_eq!(
+        machine.set_register(16, 1).unwrap_err().kind,
+        ErrorKind::InvalidRegister(16)
+    );
+}
+
+#[test]
+fn immediates_are_written_many_ways() {
+    let source = "\
+ADDI R1, R0, 0x10
+SUBI R2, R1, 0b1010
+ORI R3, R0, 'A'
+XORI R4, R3, 255
+ANDI R5, R4, -1
+ADDI R6, R5, -128
+CMPI R5, 190
+";
+    let (machine, _) = run(source, config(WordWidth::W8));
+    let registers: Vec<u32> = (1..=6).map(|r| machine.register(&Register(r))).collect();
+    assert_eq!(registers, [16, 6, 65, 190, 190, 62]);
+    assert_eq!(machine.flags(), flags("Z"));
+
+    let error = |source: &str| {
+        parse_program_with_config(source, &config(WordWidth::W16))
+            .unwrap_err()
+            .diagnostics[0]
+            .message
+            .clone()
+    };
+    assert!(parse_program_with_config(
+        "ADDI R1, R1, 65535\nADDI R1, R1, -32768\n",
+        &config(WordWidth::W16)
+    )
+    .is_ok());
+    assert_eq!(
+        error("ADDI R1, R1, 65536\n"),
+        "literal 65536 does not fit in a 16-bit word"
+    );
+    assert_eq!(
+        error("CMPI R1, -32769\n"),
+        "literal -32769 does not fit in a 16-bit word"
+    );
+}
+
+#[test]
+fn multiplication_flags_say_whether_the_product_fits() {
+    // 16 * 16 = 0x100: the low byte is 0 and the product needs the high one.
+    for name in ["MUL", "MULH", "IMUL", "IMULH"] {
+        let source = format!("SET R1, 16\n{} R2, R1, R1\n", name);
+        let (machine, _) = run(&source, config(WordWidth::W8));
+        assert_eq!(machine.flags(), flags("ZCV"), "for {}", name);
+    }
+    let (machine, _) = run("SET R1, 16\nMULH R2, R1, R1\n", config(WordWidth::W8));
+    assert_eq!(machine.register(&Register(2)), 1);
+    // -1 * 1 fits in a signed byte, whose high byte is all sign.
+    let source = "SET R1, -1\nSET R2, 1\nIMULH R3, R1, R2\n";
+    let (machine, _) = run(source, config(WordWidth::W8));
+    assert_eq!(machine.register(&Register(3)), 0xff);
+    assert_eq!(machine.flags(), flags("N"));
+
+    // The low half wraps at every width.
+    for width in WIDTHS {
+        let source = format!("SET R1, {}\nMUL R2, R1, R1\n", 1 << (width.bits() / 2));
+        let (machine, _) = run(&source, config(width));
+        assert_eq!(machine.register(&Register(2)), 0, "at {}", width);
+    }
+}
+
+#[test]
+fn division_by_zero_says_where() {
+    for name in ["DIV", "MOD", "IDIV", "IMOD"] {
+        let source = format!("SET R1, 5\n{} R2, R1, R3\n", name);
+        let (machine, stopped) = run(&source, config(WordWidth::W16));
+        assert_eq!(stopped.kind, ErrorKind::DivideByZero(1), "for {}", name);
+        let location = stopped.location.unwrap();
+        assert_eq!(
+            (location.address, location.span.line),
+            (1, 2),
+            "for {}",
+            name
+        );
+        assert_eq!(machine.register(&Register(2)), 0, "for {}", name);
+    }
+}
+
+#[test]
+fn shifts_by_nothing_by_the_width_and_by_more() {
+    for width in WIDTHS {
+        let bits = width.bits();
+        let sign = width.sign_bit();
+        let ends = sign | 1;
+        let all = width.mask();
+        // R1 has its top and bottom bit set, and C and V are set beforehand.
+        let shift = |name: &str, amount: u32| {
+            let source = |operand: String| {
+                format!(
+                    "SET R4, {}\nADD R4, R4, R4\nSET R1, {}\nSET R2, {}\n{} R1, {}\n",
+                    sign, ends, amount, name, operand
+                )
+            };
+            let (machine, _) = run(&source(amount.to_string()), config(width));
+            let result = (machine.register(&Register(1)), machine.flags());
+            // The amount in a register means the same.
+            let (machine, _) = run(&source("R2".to_string()), config(width));
+            let by_register = (machine.register(&Register(1)), machine.flags());
+            assert_eq!(result, by_register, "for {} at {}", name, width);
+            result
+        };
+
+        // A shift by 0 keeps the carry, but like every shift clears V.
+        for name in ["SHL", "SHR", "SAR", "ROL", "ROR"] {
+            assert_eq!(shift(name, 0), (ends, flags("CN")), "{} at {}", name, width);
+        }
+        assert_eq!(shift("SHL", 1), (2, flags("C")), "at {}", width);
+        assert_eq!(shift("SHR", 1), (ends >> 1, flags("C")), "at {}", width);
+
+        // By the width, the carry gets the last bit out.
+        assert_eq!(shift("SHL", bits), (0, flags("ZC")), "at {}", width);
+        assert_eq!(shift("SHR", bits), (0, flags("ZC")), "at {}", width);
+        assert_eq!(shift("SAR", bits), (all, flags("CN")), "at {}", width);
+        assert_eq!(shift("ROL", bits), (ends, flags("CN")), "at {}", width);
+
+        // By more, every bit is gone before the last step.
+        assert_eq!(shift("SHL", bits + 1), (0, flags("Z")), "at {}", width);
+        assert_eq!(shift("SHR", bits + 1), (0, flags("Z")), "at {}", width);
+        assert_eq!(shift("SAR", bits + 1), (all, flags("CN")), "at {}", width);
+        assert_eq!(
+            shift("ROR", bits + 1),
+            ((ends >> 1) | sign, flags("CN")),
+            "at {}",
+            width
+        );
+    }
+}
+
+#[test]
+fn ports_reach_their_devices() {
+    // Port 0 reads 3, 1, 4 and port 1 records what is written.
+    let source = "IN R1, 0\nIN R2, 0\nADD R3, R1, R2\nOUT 1, R3\nOUT 1, R1\n";
+    let config = config(WordWidth::W8);
+    let mut machine = Machine::with_config(config.clone());
+    let output = OutputBuffer::new();
+    machine.attach_device(0, Box::new(InputQueue::new([3, 1, 4])));
+    machine.attach_device(1, Box::new(output.clone()));
+    machine
+        .init_program(parse_program_with_config(source, &config).unwrap())
+        .unwrap();
+    assert_eq!(finish(&mut machine).kind, ErrorKind::EndOfProgram);
+    assert_eq!(output.writes(), [(1, 4), (1, 3)]);
+
+    let (_, stopped) = run("IN R1, 2\n", config.clone());
+    assert_eq!(stopped.kind, ErrorKind::NoDevice(2));
+    let (_, stopped) = run("SET R1, 1\nOUT 255, R1\n", config.clone());
+    assert_eq!(stopped.kind, ErrorKind::NoDevice(255));
+
+    // Text goes through one byte at a time, and writes to an input are
+    // dropped.
+    let source = "loop:\nIN R1, 0\nOUT 0, R1\nOUT 1, R1\nJ loop\n";
+    let mut machine = Machine::with_config(config.clone());
+    let output = OutputBuffer::new();
+    machine.attach_device(0, Box::new(InputQueue::from_text("hi!")));
+    machine.attach_device(1, Box::new(output.clone()));
+    machine
+        .init_program(parse_program_with_config(source, &config).unwrap())
+        .unwrap();
+    assert_eq!(finish(&mut machine).kind, ErrorKind::NoInput(0));
+    assert_eq!(output.text(), "hi!");
+    assert!(machine.detach_device(1).is_some());
+    assert!(machine.detach_device(1).is_none());
+}
+
+#[test]
+fn halt_gives_the_exit_code() {
+    let (mut machine, stopped) = run("SET R1, 42\nHALT R1\nSET R1, 1\n", config(WordWidth::W8));
+    assert_eq!(stopped.kind, ErrorKind::Halted(42));
+    // The machine stays on the HALT.
+    assert_eq!(machine.run(), RunOutcome::Halted(42));
+    assert_eq!(machine.register(&Register(1)), 42);
+    let (_, stopped) = run("HALT\n", config(WordWidth::W8));
+    assert_eq!(stopped.kind, ErrorKind::Halted(0));
+    let (_, stopped) = run("SET R1, 321\nHALT R1\n", config(WordWidth::W16));
+    assert_eq!(RunOutcome::from(stopped), RunOutcome::Halted(321));
+
+    assert_eq!(RunOutcome::Halted(321).exit_code(), 65);
+    assert_eq!(RunOutcome::Halted(255).exit_code(), 255);
+    assert_eq!(RunOutcome::EndOfProgram.exit_code(), 0);
+    let (_, stopped) = run("RET\n", config(WordWidth::W8));
+    assert_eq!(RunOutcome::from(stopped).exit_code(), 70);
+}
+
+#[test]
+fn indirect_jumps_stay_in_the_program() {
+    let source = "LA R1, there\nJR R1\nHALT\nthere:\nHALT R1\n";
+    let (_, stopped) = run(source, config(WordWidth::W8));
+    assert_eq!(stopped.kind, ErrorKind::Halted(3));
+    // The address just past the last instruction is the end of the program.
+    let (_, stopped) = run("SET R1, 2\nJR R1\n", config(WordWidth::W8));
+    assert_eq!(stopped.kind, ErrorKind::EndOfProgram);
+    let (_, stopped) = run("SET R1, 3\nJR R1\n", config(WordWidth::W8));
+    assert_eq!(stopped.kind, ErrorKind::InvalidJumpTarget(3));
+    let (_, stopped) = run("SUBI R1, R0, 1\nJR R1\n", config(WordWidth::W32));
+    assert_eq!(stopped.kind, ErrorKind::InvalidJumpTarget(u32::MAX));
+}
+
+#[test]
+fn labels_are_resolved_when_the_program_is_loaded() {
+    let source = "start:\nJ there\nHALT\nthere:\nlast:\nJNZ start\n";
+    let program = ResolvedProgram::resolve(parse_program(source).unwrap()).unwrap();
+    assert_eq!(program.len(), 3);
+    assert_eq!(program.label_address("start"), Some(0));
+    assert_eq!(program.label_address("there"), Some(2));
+    assert_eq!(program.label_address("last"), Some(2));
+    assert_eq!(program.label_address("nowhere"), None);
+    assert_eq!(
+        (program.target(0), program.target(1), program.target(2)),
+        (2, 0, 0)
+    );
+
+    // A jump to a missing label fails the load, even if it is never taken.
+    let mut lines = parse_program(source).unwrap();
+    lines.retain(|line| !matches!(line, ProgramLine::Lbl(label, _) if label.0 == "there"));
+    let mut machine = Machine::new();
+    let error = machine.init_program(lines).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::MissingLabel("there".to_string()));
+    let location = error.location.unwrap();
+    assert_eq!((location.address, location.span.line), (0, 2));
+    assert_eq!(location.instruction, "J there");
+}
+
+#[test]
+fn compare_only_sets_the_flags() {
+    for width in WIDTHS {
+        let set = "SET R1, 3\nSET R2, 5\n";
+        let (machine, _) = run(&format!("{}CMP R1, R2\n", set), config(width));
+        assert_eq!(machine.flags(), flags("CN"), "at {}", width);
+        assert_eq!(machine.register(&Register(1)), 3, "at {}", width);
+        assert_eq!(machine.register(&Register(2)), 5, "at {}", width);
+        // Nothing is pushed on the stack either.
+        assert!(
+            machine.memory().iter().all(|&byte| byte == 0),
+            "at {}",
+            width
+        );
+
+        let (machine, _) = run(&format!("{}CMP R2, R1\n", set), config(width));
+        assert_eq!(machine.flags(), flags(""), "at {}", width);
+        let source = format!("{}BEQ R1, R1, same\nSET R3, 1\nsame:\n", set);
+        let (machine, _) = run(&source, config(width));
+        assert_eq!(machine.register(&Register(3)), 0, "at {}", width);
+        assert_eq!(machine.flags(), flags("Z"), "at {}", width);
+    }
+}
@@ -0,0 +1,256 @@
+//! Checks what the parser makes of valid source: relaxed spellings mean the
+//! same as the canonical one, and every line knows where it came from.
+
+use asm_virtual_machine::machine::{Machine, ProgramLine, RunOutcome};
+use asm_virtual_machine::parser::{parse_file, parse_program, parse_program_with_config};
+
+/// The parsed lines as text, without their positions.
+fn lines(source: &str) -> Vec<String> {
+    parse_program(source)
+        .unwrap()
+        .into_iter()
+        .map(|line| match line {
+            ProgramLine::Ins(instruction, _) => instruction.to_string(),
+            ProgramLine::Lbl(label, _) => format!("{}:", label.0),
+            ProgramLine::Alias(name, register, _) => format!(".alias {} {}", name, register),
+            ProgramLine::Global(label, _) => format!(".global {}", label.0),
+            ProgramLine::Data(address, bytes, _) => format!("{}: {:?}", address, bytes),
+            ProgramLine::DataLabel(label, address, _) => format!("{} = {}", label.0, address),
+        })
+        .collect()
+}
+
+#[test]
+fn relaxed_syntax_means_the_same() {
+    let canonical = "\
+.alias cnt R2
+SET R1, 5
+loop:
+ADD R2, R2, R1
+DEC R1
+JNZ loop
+LOAD R3, [0x10]
+HALT R2
+";
+    let relaxed = "\
+\t.ALIAS  cnt   r2   # the running total\r
+set r1,5 // five, not six\r
+\r
+loop:\tadd cnt ,cnt,  R1 ; it's ü-nicode\r
+    Dec\tr1\r
+  jnz loop\r
+load r3 , [ 0X10 ]\r
+   halt cnt   ";
+    assert_eq!(lines(canonical), lines(relaxed));
+}
+
+#[test]
+fn label_and_instruction_share_a_line() {
+    assert_eq!(
+        lines("a: b: INC R1\nc: ; nothing here\nJ a\n"),
+        vec!["a:", "b:", "INC R1", "c:", "J a"]
+    );
+}
+
+#[test]
+fn lines_know_where_they_came_from() {
+    let source = "loop: INC R1 ; count\n\tJ loop\n";
+    let program = parse_file("count.asm", source, &Default::default()).unwrap();
+    let spans: Vec<String> = program
+        .iter()
+        .map(|line| {
+            let span = line.span();
+            format!("{}-{}", span, span.end_column)
+        })
+        .collect();
+    assert_eq!(
+        spans,
+        ["count.asm:1:1-6", "count.asm:1:7-13", "count.asm:2:2-8"]
+    );
+
+    let mut machine = Machine::new();
+    let config = Default::default();
+    machine
+        .init_program(parse_program_with_config(source, &config).unwrap())
+        .unwrap();
+    machine.step().unwrap();
+    let span = machine.current_span().unwrap();
+    assert_eq!((span.line, span.column, span.end_column), (2, 2, 8));
+}
+
+#[test]
+fn constants_and_expressions_are_evaluated() {
+    let source = "\
+.equ BASE, 0x10
+.define COUNT 3
+.equ LIMIT, COUNT * 4 + 1
+start:
+SET R1, LIMIT
+SET R2, ~(1 << 7) & 0xFF
+SHL R1, COUNT - 2
+SHL R1, R2
+STORE R1, [BASE + 2]
+LOAD R3, [R2]
+SET R4, end - start
+OUT BASE >> 4 | 0, R4
+end:
+";
+    assert_eq!(
+        lines(source),
+        [
+            "start:",
+            "SET R1, 13",
+            "SET R2, 127",
+            "SHL R1, 1",
+            "SHL R1, R2",
+            "STORE R1, [18]",
+            "LOAD R3, [R2]",
+            "SET R4, 8",
+            "OUT 1, R4",
+            "end:",
+        ]
+    );
+}
+
+#[test]
+fn macros_expand_where_they_are_called() {
+    let source = "\
+.macro COUNTDOWN reg, step
+again: SUBI reg, reg, step ; labels are new in every call
+    JNZ again
+.endm
+countdown R1, 2
+COUNTDOWN R2, 1 + 1
+";
+    assert_eq!(
+        lines(source),
+        [
+            "COUNTDOWN.1.again:",
+            "SUBI R1, R1, 2",
+            "JNZ COUNTDOWN.1.again",
+            "COUNTDOWN.2.again:",
+            "SUBI R2, R2, 2",
+            "JNZ COUNTDOWN.2.again",
+        ]
+    );
+
+    let program = parse_program(source).unwrap();
+    let span = program[4].span();
+    assert_eq!((span.line, span.column), (2, 8));
+    assert_eq!(
+        span.to_string(),
+        "line 2 in macro COUNTDOWN called at line 6"
+    );
+
+    // Local labels can have a '-' like any other, and parameters still
+    // work next to one.
+    let source = "\
+.macro WAIT n
+my-loop: SUBI R1, R1, n-1
+    JNZ my-loop
+.endm
+WAIT 2
+WAIT 3
+";
+    assert_eq!(
+        lines(source),
+        [
+            "WAIT.1.my-loop:",
+            "SUBI R1, R1, 1",
+            "JNZ WAIT.1.my-loop",
+            "WAIT.2.my-loop:",
+            "SUBI R1, R1, 2",
+            "JNZ WAIT.2.my-loop",
+        ]
+    );
+}
+
+#[test]
+fn data_is_laid_out_in_memory() {
+    let source = "\
+.data
+text:   .asciz \"a;\\n\"
+.text
+        LOADB R1, [table + 1]
+        HALT R1
+.data
+table:  .byte 7, -1
+words:  .word 0x12, end - text
+        .space 2
+end:
+.data 0x20
+        .string \"\"
+";
+    assert_eq!(
+        lines(source),
+        [
+            "text = 0",
+            "0: [97, 59, 10, 0]",
+            "LOADB R1, [5]",
+            "HALT R1",
+            "table = 4",
+            "4: [7, 255]",
+            "words = 6",
+            "6: [18, 10]",
+            "8: [0, 0]",
+            "end = 10",
+            "32: [0]",
+        ]
+    );
+
+    let mut machine = Machine::new();
+    machine
+        .init_program(parse_program(source).unwrap())
+        .unwrap();
+    assert!(machine.get_string_memory().starts_with(
+        "0000: 61 3b 0a 00 07 ff 12 0a 00 00 00 00 00 00 00 00  \
+         ; text at 0000, table at 0004, words at 0006, end at 000a\n"
+    ));
+    assert_eq!(machine.run(), RunOutcome::Halted(255));
+}
+
+#[test]
+fn pseudo_instructions_expand_in_place() {
+    let source = "\
+start:  LI R1, R2, -2
+        neg r3
+        CMP R1, R2
+        BNE R1, R2, end
+        CLR R4
+        BEQ R4, R0, end
+        NOP
+end:    SET R5, end - start
+        HALT R2
+";
+    assert_eq!(
+        lines(source),
+        [
+            "start:",
+            "SET R1, 254",
+            "SET R2, 255",
+            "NOT R3",
+            "INC R3",
+            "CMP R1, R2",
+            "CMP R1, R2",
+            "JNZ end",
+            "ZERO R4",
+            "CMP R4, R0",
+            "JZ end",
+            "MOV R0, R0",
+            "end:",
+            "SET R5, 11",
+            "HALT R2",
+        ]
+    );
+
+    let mut machine = Machine::new();
+    machine
+        .init_program(parse_file("pseudo.asm", source, &Default::default()).unwrap())
+        .unwrap();
+    assert!(machine.program().listing().contains(
+        "0001  SET R2, 255              ; pseudo.asm:1:9\n\
+         0002  NOT R3                   ; pseudo.asm:2:9\n\
+         0003  INC R3                   ; pseudo.asm:2:9\n"
+    ));
+    assert_eq!(machine.run(), RunOutcome::Halted(255));
+}
@@ -1,44 +1,212 @@
 use std::fs::File;
 use std::io::prelude::*;
+use std::process::ExitCode;
 
-use asm_virtual_machine::machine::Machine;
-use asm_virtual_machine::parser::parse_program;
+use asm_virtual_machine::format::format_file;
+use asm_virtual_machine::io::{Console, ConsoleMode};
+use asm_virtual_machine::linker::link;
+use asm_virtual_machine::machine::{Machine, MachineConfig, RunOutcome};
+use asm_virtual_machine::parser::parse_module;
+use asm_virtual_machine::word::WordWidth;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
+/// Runs an assembly program. Port 0 reads and writes characters on the
+/// terminal, port 1 reads and writes decimal numbers, one per line.
+///
+/// A program can be split over several files, which are assembled on their
+/// own and linked in the order they are given. The program starts at the
+/// first instruction of the first file. `fmt` lays the files out the
+/// canonical way instead of running them.
+///
+/// The exit code is the code given to HALT, 0 when the program runs past its
+/// last instruction, 65 when the program has syntax errors or does not link
+/// and 70 when it stops with a runtime error.
+///
+/// HALT codes are taken modulo 256, so a program that halts with 65 or 70
+/// (or 321, or 326) exits with the same code as a broken one. Only errors
+/// write to standard error, which tells the two apart.
 #[derive(Parser)]
-#[command(about, long_about = None)]
+#[command(
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
-    // Name of file to intepret
-    filename: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Names of the files to intepret
+    #[arg(required = true)]
+    filenames: Vec<String>,
 
     // Turn on verbose printing
     #[arg(short, long)]
     verbose: bool,
+
+    // Print every instruction with its address before running the program
+    #[arg(short, long)]
+    list: bool,
+
+    // Print the data memory after the program has finished
+    #[arg(short, long)]
+    memory: bool,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+
+    // Stop the program after this many steps, 0 for no limit
+    #[arg(long, default_value_t = 100_000_000)]
+    max_steps: u64,
+
+    // Stop programs that are stuck in an infinite loop (slower)
+    #[arg(long)]
+    detect_loops: bool,
+}
+
+/// The parts of the machine that decide whether a program is valid.
+#[derive(Args)]
+struct MachineArgs {
+    // Word width of the machine in bits (8, 16 or 32)
+    #[arg(short, long, default_value = "8")]
+    width: WordWidth,
+
+    // Number of registers in the register file
+    #[arg(short, long, default_value_t = 8)]
+    registers: usize,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Rewrites assembly files in the canonical layout, without changing
+    /// what they do.
+    ///
+    /// Exits with 1 if `--check` finds a file that is not formatted and 65
+    /// if a file has errors.
+    Fmt {
+        // Names of the files to format
+        #[arg(required = true)]
+        filenames: Vec<String>,
+
+        // Only list the files that are not formatted, without changing them
+        #[arg(long)]
+        check: bool,
+
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
 }
 
-fn main() -> anyhow::Result<()> {
+/// Formats every file in `filenames` in place, or with `check` lists the
+/// ones that would change.
+fn fmt(filenames: &[String], check: bool, config: &MachineConfig) -> anyhow::Result<ExitCode> {
+    let mut failed = false;
+    let mut unformatted = false;
+    for filename in filenames {
+        let content = std::fs::read_to_string(filename)?;
+        match format_file(filename, &content, config) {
+            Ok(formatted) if formatted == content => {}
+            Ok(_) if check => {
+                println!("{}", filename);
+                unformatted = true;
+            }
+            Ok(formatted) => std::fs::write(filename, formatted)?,
+            Err(error) => {
+                eprint!("{}", error.render(&content));
+                failed = true;
+            }
+        }
+    }
+    Ok(if failed {
+        ExitCode::from(65)
+    } else if unformatted {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+fn main() -> anyhow::Result<ExitCode> {
     let cli = Cli::parse();
 
-    let mut file = File::open(cli.filename)?;
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
-    let program = parse_program(&content)?;
-    let mut machine = Machine::new();
-    machine.init_program(program);
-    let mut r = Ok(());
-    while r == Ok(()) {
-        if cli.verbose {
-            machine.print_current_instruction();
+    if let Some(Command::Fmt {
+        filenames,
+        check,
+        machine,
+    }) = &cli.command
+    {
+        let config = MachineConfig {
+            word_width: machine.width,
+            register_count: machine.registers,
+            ..MachineConfig::default()
+        };
+        return fmt(filenames, *check, &config);
+    }
+
+    let config = MachineConfig {
+        word_width: cli.machine.width,
+        register_count: cli.machine.registers,
+        step_limit: (cli.max_steps > 0).then_some(cli.max_steps),
+        detect_loops: cli.detect_loops,
+        ..MachineConfig::default()
+    };
+    let mut modules = Vec::new();
+    let mut failed = false;
+    for filename in &cli.filenames {
+        let mut file = File::open(filename)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        match parse_module(filename, &content, &config) {
+            Ok(module) => modules.push(module),
+            Err(error) => {
+                eprint!("{}", error.render(&content));
+                failed = true;
+            }
         }
-        r = machine.step();
-        if cli.verbose {
+    }
+    if failed {
+        return Ok(ExitCode::from(65));
+    }
+    let program = match link(modules) {
+        Ok(program) => program,
+        Err(error) => {
+            // Every module has a file name, so the error has all the sources.
+            eprint!("{}", error.render(""));
+            return Ok(ExitCode::from(65));
+        }
+    };
+    let mut machine = Machine::with_config(config);
+    machine.attach_device(0, Box::new(Console::new(ConsoleMode::Characters)));
+    machine.attach_device(1, Box::new(Console::new(ConsoleMode::Numbers)));
+    if let Err(e) = machine.init_program(program) {
+        eprintln!("Load error: {}", e);
+        return Ok(ExitCode::from(RunOutcome::Error(e).exit_code()));
+    }
+    if cli.list {
+        print!("{}", machine.program().listing());
+        println!("------");
+    }
+    let outcome = if cli.verbose {
+        let mut r = Ok(());
+        while r == Ok(()) {
+            machine.print_current_instruction();
+            r = machine.step();
             machine.print_registers();
             println!("------");
         }
-    }
-    if !cli.verbose {
+        RunOutcome::from(r.unwrap_err())
+    } else {
+        let outcome = machine.run();
         machine.print_registers();
+        outcome
+    };
+    if cli.memory {
+        machine.print_memory();
+    }
+    match &outcome {
+        RunOutcome::Halted(code) => println!("Halted with exit code {}", code),
+        RunOutcome::EndOfProgram => {}
+        RunOutcome::Error(e) => eprintln!("Runtime error: {}", e),
     }
-    Ok(())
+    Ok(ExitCode::from(outcome.exit_code()))
 }
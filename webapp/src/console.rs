@@ -0,0 +1,70 @@
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use asm_virtual_machine::io::IoDevice;
+
+#[derive(Default)]
+struct ConsoleState {
+    input: VecDeque<u32>,
+    output: String,
+}
+
+/// Console shown in the page. Like the CLI, port 0 carries characters and
+/// port 1 decimal numbers. Clones share the same input and output.
+#[derive(Clone, Default)]
+pub struct WebConsole {
+    state: Rc<RefCell<ConsoleState>>,
+}
+
+impl WebConsole {
+    pub fn new() -> WebConsole {
+        WebConsole::default()
+    }
+
+    /// Replaces the pending input. Whitespace separated numbers are read from
+    /// port 1, every character (including the whitespace) from port 0.
+    pub fn set_input(&self, input: &str) {
+        self.state.borrow_mut().input = input.bytes().map(|b| b as u32).collect();
+    }
+
+    pub fn output(&self) -> String {
+        self.state.borrow().output.clone()
+    }
+
+    pub fn clear_output(&self) {
+        self.state.borrow_mut().output.clear();
+    }
+
+    fn read_number(input: &mut VecDeque<u32>) -> Option<u32> {
+        while input.front().is_some_and(|c| (*c as u8).is_ascii_whitespace()) {
+            input.pop_front();
+        }
+        let mut number = String::new();
+        while let Some(c) = input.front() {
+            let c = *c as u8 as char;
+            if c.is_ascii_whitespace() {
+                break;
+            }
+            number.push(c);
+            input.pop_front();
+        }
+        number.parse::<i64>().ok().map(|n| n as u32)
+    }
+}
+
+impl IoDevice for WebConsole {
+    fn read(&mut self, port: u8) -> Option<u32> {
+        let input = &mut self.state.borrow_mut().input;
+        match port {
+            1 => WebConsole::read_number(input),
+            _ => input.pop_front(),
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u32) {
+        let output = &mut self.state.borrow_mut().output;
+        match port {
+            1 => output.push_str(&format!("{}\n", value)),
+            _ => output.push(value as u8 as char),
+        }
+    }
+}
@@ -1,4 +1,9 @@
-use asm_virtual_machine::{machine::Machine, parser::parse_program};
+use asm_virtual_machine::{
+    diagnostic::Diagnostic,
+    machine::{Machine, MachineConfig, RunOutcome},
+    parser::parse_program,
+};
+use console::WebConsole;
 use log::info;
 use register_list::RegisterList;
 use textfield::TextField;
@@ -6,6 +11,7 @@ use web_sys::{HtmlInputElement, HtmlTextAreaElement};
 
 use yew::prelude::*;
 
+pub mod console;
 pub mod textfield;
 
 pub mod register_list;
@@ -14,15 +20,19 @@ pub mod register_selector;
 pub struct App {
     pub log: Vec<String>,
     pub machine: Machine,
+    pub console: WebConsole,
     pub program: String,
+    /// Problems found in `program` the last time it was run.
+    pub diagnostics: Vec<Diagnostic>,
     pub text_ref: NodeRef,
+    pub input_ref: NodeRef,
     pub check_ref: NodeRef,
 }
 
 pub enum Msg {
     RunProgram,
     ClearLog,
-    SetRegister(usize, i8),
+    SetRegister(usize, i64),
     GetRegister,
     ResetRegisters,
 }
@@ -34,15 +44,28 @@ impl Component for App {
 
     fn create(_ctx: &Context<Self>) -> Self {
         let log = Vec::new();
-        let machine = Machine::new();
+        // A stuck program must not freeze the tab.
+        let mut machine = Machine::with_config(MachineConfig {
+            step_limit: Some(1_000_000),
+            detect_loops: true,
+            ..MachineConfig::default()
+        });
+        let console = WebConsole::new();
+        machine.attach_device(0, Box::new(console.clone()));
+        machine.attach_device(1, Box::new(console.clone()));
         let program = String::default();
+        let diagnostics = Vec::new();
         let text_ref = NodeRef::default();
+        let input_ref = NodeRef::default();
         let check_ref = NodeRef::default();
         App {
             log,
             machine,
+            console,
             program,
+            diagnostics,
             text_ref,
+            input_ref,
             check_ref,
         }
     }
@@ -52,13 +75,24 @@ impl Component for App {
             Msg::RunProgram => {
                 let input_val = &self.text_ref.cast::<HtmlTextAreaElement>().unwrap().value();
                 let verbose = &self.check_ref.cast::<HtmlInputElement>().unwrap().checked();
+                let input = self.input_ref.cast::<HtmlInputElement>().unwrap().value();
 
                 info!("{}", input_val.to_string());
 
-                let Ok(program) = parse_program(input_val) else {
-                    return true;
+                self.program = input_val.clone();
+                let program = match parse_program(input_val) {
+                    Ok(program) => program,
+                    Err(error) => {
+                        self.diagnostics = error.diagnostics;
+                        return true;
+                    }
                 };
-                self.machine.init_program(program);
+                self.diagnostics.clear();
+                if let Err(e) = self.machine.init_program(program) {
+                    self.log.push(format!("Load error: {}", e));
+                    return true;
+                }
+                self.console.set_input(&input);
                 let mut r = Ok(());
                 while r == Ok(()) {
                     let ins = self.machine.get_current_instruction();
@@ -73,11 +107,19 @@ impl Component for App {
                     let regs = self.machine.get_string_registers();
                     self.log.push(regs);
                 }
+                match RunOutcome::from(r.unwrap_err()) {
+                    RunOutcome::Halted(code) => {
+                        self.log.push(format!("Halted with exit code {}", code))
+                    }
+                    RunOutcome::EndOfProgram => {}
+                    RunOutcome::Error(e) => self.log.push(format!("Runtime error: {}", e)),
+                }
 
                 true
             }
             Msg::ClearLog => {
                 self.log = Vec::new();
+                self.console.clear_output();
                 true
             }
             Msg::SetRegister(r, v) => {
@@ -91,8 +133,8 @@ impl Component for App {
                 true
             }
             Msg::ResetRegisters => {
-                for i in 0..7 {
-                    let _ = self.machine.set_register(i as usize, 0);
+                for i in 0..self.machine.register_count() {
+                    let _ = self.machine.set_register(i, 0);
                 }
                 true
             }
@@ -112,10 +154,23 @@ impl Component for App {
                 }
             })
             .collect::<Html>();
+        let diagnostics = self
+            .diagnostics
+            .iter()
+            .map(|diagnostic| html! {<pre class={classes!("diagnostic")}>{diagnostic.render(&self.program)}</pre>})
+            .collect::<Html>();
+        let errors: Vec<(usize, String)> = self
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.span.line, diagnostic.message.clone()))
+            .collect();
         html! {
             <>
                 <h1>{"Comparc Interpreter"}</h1>
-                <TextField node_ref={&self.text_ref} />
+                <div class={classes!("program")}>
+                    <TextField node_ref={&self.text_ref} {errors} />
+                    {diagnostics}
+                </div>
                 <div class={classes!("control")}>
                     <button onclick={ctx.link().callback(|_| Msg::RunProgram)}>{"Run Program"}</button>
                     <button onclick={ctx.link().callback(|_| Msg::ClearLog)}>{"Clear Log"}</button>
@@ -124,7 +179,12 @@ impl Component for App {
                     <label for="verbose">{"Verbose mode"}</label>
                     <input type="checkbox" ref={&self.check_ref} name="verbose" />
                 </div>
-                <RegisterList callback={ctx.link().callback(|(r,v)| Msg::SetRegister(r,v))}/>
+                <div class={classes!("console")}>
+                    <label for="input">{"Input"}</label>
+                    <input type="text" ref={&self.input_ref} name="input" />
+                    <pre>{self.console.output()}</pre>
+                </div>
+                <RegisterList count={self.machine.register_count()} callback={ctx.link().callback(|(r,v)| Msg::SetRegister(r,v))}/>
                 <div class={classes!("log")}>
                     {log}
                 </div>
@@ -6,11 +6,12 @@ pub struct RegisterList;
 
 #[derive(Properties, PartialEq)]
 pub struct Props {
-    pub callback: Callback<(usize, i8)>,
+    pub count: usize,
+    pub callback: Callback<(usize, i64)>,
 }
 
 impl Component for RegisterList {
-    type Message = (usize, i8);
+    type Message = (usize, i64);
 
     type Properties = Props;
 
@@ -24,16 +25,14 @@ impl Component for RegisterList {
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
+        let selectors = (0..ctx.props().count).map(|r| {
+            html! {
+                <RegisterSelector register={r} callback={ctx.link().callback(move |v| (r,v))} />
+            }
+        });
         html! {
             <div class={classes!("regs")}>
-                <RegisterSelector register={0} callback={ctx.link().callback(|v| (0,v))} />
-                <RegisterSelector register={1} callback={ctx.link().callback(|v| (1,v))} />
-                <RegisterSelector register={2} callback={ctx.link().callback(|v| (2,v))} />
-                <RegisterSelector register={3} callback={ctx.link().callback(|v| (3,v))} />
-                <RegisterSelector register={4} callback={ctx.link().callback(|v| (4,v))} />
-                <RegisterSelector register={5} callback={ctx.link().callback(|v| (5,v))} />
-                <RegisterSelector register={6} callback={ctx.link().callback(|v| (6,v))} />
-                <RegisterSelector register={7} callback={ctx.link().callback(|v| (7,v))} />
+                {for selectors}
             </div>
         }
     }
@@ -4,7 +4,7 @@ use yew::prelude::*;
 #[derive(Properties, PartialEq)]
 pub struct Props {
     pub register: usize,
-    pub callback: Callback<i8>,
+    pub callback: Callback<i64>,
 }
 
 pub struct RegisterSelector {
@@ -28,7 +28,7 @@ impl Component for RegisterSelector {
             .cast::<HtmlInputElement>()
             .unwrap()
             .value()
-            .parse::<i8>()
+            .parse::<i64>()
             .ok()
         else {
             return false;
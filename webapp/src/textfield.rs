@@ -1,27 +1,89 @@
+use web_sys::{Element, HtmlTextAreaElement};
 use yew::prelude::*;
 
-pub struct TextField;
+/// The program editor: a text area with line numbers, where the lines with
+/// errors are marked.
+pub struct TextField {
+    /// Number of lines in the text area.
+    lines: usize,
+    gutter_ref: NodeRef,
+}
+
+pub enum Msg {
+    Edited,
+    Scrolled,
+}
 
 #[derive(Properties, PartialEq)]
 pub struct Props {
     pub node_ref: NodeRef,
+    /// Lines to mark, counted from 1, with what is wrong with them.
+    #[prop_or_default]
+    pub errors: Vec<(usize, String)>,
 }
 
 impl Component for TextField {
-    type Message = ();
+    type Message = Msg;
 
     type Properties = Props;
 
     fn create(_ctx: &Context<Self>) -> Self {
-        Self
+        Self {
+            lines: 1,
+            gutter_ref: NodeRef::default(),
+        }
+    }
+
+    fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
+        let Some(text) = ctx.props().node_ref.cast::<HtmlTextAreaElement>() else {
+            return false;
+        };
+        match msg {
+            Msg::Edited => {
+                let lines = text.value().split('\n').count();
+                let changed = lines != self.lines;
+                self.lines = lines;
+                changed
+            }
+            // The line numbers scroll with the text.
+            Msg::Scrolled => {
+                if let Some(gutter) = self.gutter_ref.cast::<Element>() {
+                    gutter.set_scroll_top(text.scroll_top());
+                }
+                false
+            }
+        }
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
+        let errors = &ctx.props().errors;
+        let numbers = (1..=self.lines).map(|line| {
+            let messages: Vec<&str> = errors
+                .iter()
+                .filter(|(error_line, _)| *error_line == line)
+                .map(|(_, message)| message.as_str())
+                .collect();
+            let error = (!messages.is_empty()).then_some("error");
+            html! {
+                <div class={classes!("line-number", error)} title={messages.join("\n")}>
+                    {line}
+                </div>
+            }
+        });
         html! {
-            <textarea class={classes!("program")}
-                ref={&ctx.props().node_ref}
-                type="text"
-                />
+            <div class={classes!("editor")}>
+                <div class={classes!("gutter")} ref={&self.gutter_ref}>
+                    {for numbers}
+                </div>
+                <textarea class={classes!("source")}
+                    ref={&ctx.props().node_ref}
+                    type="text"
+                    wrap="off"
+                    spellcheck="false"
+                    oninput={ctx.link().callback(|_| Msg::Edited)}
+                    onscroll={ctx.link().callback(|_| Msg::Scrolled)}
+                    />
+            </div>
         }
     }
 }